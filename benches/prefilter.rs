@@ -0,0 +1,110 @@
+//! Literal prefilter on a synthetic log corpus, prefilter on vs off.
+//!
+//! The corpus is searched line by line, so the cost of calling into PCRE2 adds
+//! up. Patterns whose literals are rare skip most lines, a common one shows the
+//! cost of the extra scan.
+//!
+//! `cargo bench --bench prefilter`
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use xipin_resolution::matcher::{PCRE2Builder, PCRE2};
+
+const LINES: usize = 200_000;
+
+const LEVELS: &[&str] = &["INFO", "DEBUG", "INFO", "WARN", "INFO", "DEBUG"];
+const WORDS: &[&str] = &[
+    "request",
+    "served",
+    "upstream",
+    "latency",
+    "cache",
+    "hit",
+    "miss",
+    "user",
+    "session",
+    "connection",
+    "closed",
+    "opened",
+    "retry",
+    "timeout",
+    "done",
+];
+
+/// a small LCG, so the corpus is the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+        ((self.0 >> 33) as usize) % n
+    }
+}
+
+/// log lines, one in 5000 has a `Traceback`
+fn corpus() -> String {
+    let mut rng = Rng(2023);
+    (0..LINES)
+        .map(|i| {
+            let mut line = format!(
+                "2023-04-25 10:{:02}:{:02}.{:03} {} ",
+                i / 60 % 60,
+                i % 60,
+                rng.next(1000),
+                LEVELS[rng.next(LEVELS.len())]
+            );
+            for _ in 0..12 {
+                line.push_str(WORDS[rng.next(WORDS.len())]);
+                line.push(' ');
+            }
+            if i % 5000 == 0 {
+                line.push_str("Traceback (most recent call last)");
+            }
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+fn count(re: &PCRE2, corpus: &[u8]) -> usize {
+    corpus
+        .split(|b| *b == b'\n')
+        .map(|line| re.find_iter(line).count())
+        .sum()
+}
+
+fn bench(name: &str, re: &PCRE2, corpus: &[u8]) -> Duration {
+    // warm up
+    black_box(count(re, corpus));
+    let now = Instant::now();
+    let mut found = 0;
+    for _ in 0..5 {
+        found = black_box(count(re, corpus));
+    }
+    let elapsed = now.elapsed() / 5;
+    println!("{:<28} {:>10.2?} {:>6} matches", name, elapsed, found);
+    elapsed
+}
+
+fn main() {
+    let corpus = corpus();
+    let corpus = corpus.as_bytes();
+    println!("corpus: {} lines, {} bytes", LINES, corpus.len());
+
+    for pattern in [
+        r"Traceback \(",
+        r"upstream (\w+) Traceback",
+        r"timeout (\w+) retry",
+    ] {
+        let on = PCRE2Builder::new().build(pattern).unwrap();
+        let off = PCRE2Builder::new().prefilter(false).build(pattern).unwrap();
+        println!("\n{} prefilter: {:?}", pattern, on.prefilter());
+        let with = bench("prefilter", &on, corpus);
+        let without = bench("no prefilter", &off, corpus);
+        println!(
+            "speedup: {:.2}x",
+            without.as_secs_f64() / with.as_secs_f64()
+        );
+    }
+}
@@ -114,7 +114,7 @@ fn main() {
         builder.file(Path::new("pcre2/src").join(file));
     }
 
-    if env::var("PCRE2_SYS_DEBUG").unwrap_or(String::new()) == "1" {
+    if env::var("PCRE2_SYS_DEBUG").unwrap_or_default() == "1" {
         builder.debug(true);
     }
     builder.compile("pcre2");
@@ -4,8 +4,8 @@ mod bindings;
 pub use bindings::*;
 
 // STRANGE: didn't auto binding
-pub const PCRE2_UNSET: usize = ::std::usize::MAX;
-pub const PCRE2_ZERO_TERMINATED: usize = ::std::usize::MAX;
+pub const PCRE2_UNSET: usize = usize::MAX;
+pub const PCRE2_ZERO_TERMINATED: usize = usize::MAX;
 pub type PCRE2_SIZE = usize;
 
 #[cfg(test)]
@@ -14,6 +14,37 @@ mod tests {
 
     #[test]
     fn test_dfa_match() {
-        unsafe {}
+        let pattern = b"\\d{4}";
+        let subject = b"abc2023def";
+        let (mut error_code, mut error_offset) = (0, 0);
+        let mut workspace = [0 as ::libc::c_int; 64];
+        unsafe {
+            let code = pcre2_compile_8(
+                pattern.as_ptr(),
+                pattern.len(),
+                0,
+                &mut error_code,
+                &mut error_offset,
+                ::std::ptr::null_mut(),
+            );
+            assert!(!code.is_null());
+            let data = pcre2_match_data_create_from_pattern_8(code, ::std::ptr::null_mut());
+            let rc = pcre2_dfa_match_8(
+                code,
+                subject.as_ptr(),
+                subject.len(),
+                0,
+                0,
+                data,
+                ::std::ptr::null_mut(),
+                workspace.as_mut_ptr(),
+                workspace.len(),
+            );
+            assert_eq!(rc, 1);
+            let ovector = pcre2_get_ovector_pointer_8(data);
+            assert_eq!((*ovector, *ovector.add(1)), (3, 7));
+            pcre2_match_data_free_8(data);
+            pcre2_code_free_8(code);
+        }
     }
 }
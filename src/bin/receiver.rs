@@ -0,0 +1,361 @@
+//! Receives the results of a sender and prints them, one per line.
+//!
+//! Listens on a UDP, TCP or unix socket, decodes the framing, batches and
+//! format the sender uses, and reports packet and record counts, throughput
+//! and, with `--reliable`, duplicate, early and lost packets on stderr. The
+//! address actually listened on is the first line on stderr,
+//! `listening on <addr>`, so a port of 0 works for tests.
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use xipin_resolution::encoder::Format;
+use xipin_resolution::sender::{decompress, unbatch, Framing, Key, ReliableReceiver, Verifier};
+
+const USAGE: &str = "usage: receiver [options] [url]
+
+  url                 udp://host:port, tcp://host:port, unix://path or
+                      unixgram://path, udp://127.0.0.1:7878 by default
+
+  --format FORMAT     raw, json, csv or msgpack, shown as JSON
+  --framing FRAMING   length, newline or octet, on stream sockets
+  --key-file FILE     only take payloads signed with the key in FILE
+  --key-env VAR       only take payloads signed with the key in $VAR
+  --compressed        payloads have a compression flag, gzip and zstd need
+                      the features of the same name
+  --batched           payloads are batches of records
+  --reliable          ack the packets of a reliable udp sender
+  -o, --output FILE   append the results to FILE instead of stdout
+  -n, --count N       exit after N records
+  --stats SECS        report the counts every SECS seconds
+  -h, --help          show this";
+
+const DEFAULT_URL: &str = "udp://127.0.0.1:7878";
+
+struct Options {
+    url: String,
+    format: Format,
+    framing: Framing,
+    key: Option<Key>,
+    compressed: bool,
+    batched: bool,
+    reliable: bool,
+    output: Option<PathBuf>,
+    count: Option<u64>,
+    stats: Option<Duration>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self> {
+        let mut options = Options {
+            url: DEFAULT_URL.to_string(),
+            format: Format::Raw,
+            framing: Framing::LengthPrefix,
+            key: None,
+            compressed: false,
+            batched: false,
+            reliable: false,
+            output: None,
+            count: None,
+            stats: None,
+        };
+        let mut url = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--format" => options.format = value()?.parse()?,
+                "--framing" => {
+                    options.framing = match value()?.as_str() {
+                        "length" => Framing::LengthPrefix,
+                        "newline" => Framing::Newline,
+                        "octet" => Framing::OctetCounting,
+                        framing => bail!("unknown framing: {}", framing),
+                    }
+                }
+                "--key-file" => options.key = Some(Key::from_file(value()?)?),
+                "--key-env" => options.key = Some(Key::from_env(&value()?)?),
+                "--compressed" => options.compressed = true,
+                "--batched" => options.batched = true,
+                "--reliable" => options.reliable = true,
+                "-o" | "--output" => options.output = Some(value()?.into()),
+                "-n" | "--count" => options.count = Some(value()?.parse()?),
+                "--stats" => {
+                    let secs = value()?;
+                    let interval = secs
+                        .parse()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                        .filter(|interval| !interval.is_zero())
+                        .ok_or_else(|| anyhow!("--stats needs a positive number: {}", secs))?;
+                    options.stats = Some(interval);
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ if arg.starts_with('-') => bail!("unknown option: {}", arg),
+                _ if url.is_none() => url = Some(arg),
+                _ => bail!("unexpected argument: {}", arg),
+            }
+        }
+        if let Some(url) = url {
+            options.url = url;
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    packets: AtomicU64,
+    records: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+    /// the counts of [`ReliableReceiver`], only known with `--reliable`
+    reliable: Option<ReliableStats>,
+}
+
+#[derive(Default)]
+struct ReliableStats {
+    duplicates: AtomicU64,
+    /// packets that came in ahead of a missing one
+    early: AtomicU64,
+    /// packets the sender gave up on
+    lost: AtomicU64,
+}
+
+impl Stats {
+    fn report(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64().max(1e-3);
+        let records = self.records.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let reliable = self.reliable.as_ref().map_or(String::new(), |r| {
+            format!(
+                " duplicates {} early {} lost {}",
+                r.duplicates.load(Ordering::Relaxed),
+                r.early.load(Ordering::Relaxed),
+                r.lost.load(Ordering::Relaxed),
+            )
+        });
+        format!(
+            "packets {} records {} bytes {} errors {} rejected {}{}, {:.1} records/s {:.1} KiB/s",
+            self.packets.load(Ordering::Relaxed),
+            records,
+            bytes,
+            self.errors.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            reliable,
+            records as f64 / secs,
+            bytes as f64 / 1024.0 / secs,
+        )
+    }
+}
+
+struct Receiver {
+    options: Options,
+    verifier: Option<Verifier>,
+    out: Mutex<Box<dyn Write + Send>>,
+    stats: Stats,
+    start: Instant,
+}
+
+impl Receiver {
+    fn new(mut options: Options) -> Result<Self> {
+        let out: Box<dyn Write + Send> = match &options.output {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout()),
+        };
+        let stats = Stats {
+            reliable: options.reliable.then(ReliableStats::default),
+            ..Stats::default()
+        };
+        Ok(Self {
+            verifier: options.key.take().map(Verifier::new),
+            options,
+            out: Mutex::new(out),
+            stats,
+            start: Instant::now(),
+        })
+    }
+
+    /// take in one packet or frame, a bad one is counted and skipped
+    fn deliver(&self, payload: &[u8]) {
+        self.stats.packets.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        let payload = match self.verifier.as_ref().map(|v| v.verify(payload)) {
+            Some(Ok(payload)) => payload,
+            Some(Err(e)) => {
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                eprintln!("rejected payload: {}", e);
+                return;
+            }
+            None => payload,
+        };
+        if let Err(e) = self.write(payload) {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            eprintln!("bad payload: {:?}", e);
+        }
+    }
+
+    fn write(&self, payload: &[u8]) -> Result<()> {
+        let payload = match self.options.compressed {
+            true => decompress(payload)?,
+            false => Cow::Borrowed(payload),
+        };
+        let records = match self.options.batched {
+            true => unbatch(&payload)?,
+            false => vec![&payload[..]],
+        };
+        let count = records.len() as u64;
+        let mut text = Vec::new();
+        for record in records {
+            self.options.format.to_text(record, &mut text)?;
+            text.push(b'\n');
+        }
+
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&text)?;
+        out.flush()?;
+        let total = self.stats.records.fetch_add(count, Ordering::Relaxed) + count;
+        if self.options.count.is_some_and(|count| total >= count) {
+            self.finish();
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> ! {
+        eprintln!("{}", self.stats.report(self.start.elapsed()));
+        process::exit(0);
+    }
+
+    /// frames until the peer closes the connection
+    fn read_stream<R: Read>(&self, conn: R) {
+        let mut reader = BufReader::new(conn);
+        loop {
+            match self.options.framing.decode(&mut reader) {
+                Ok(Some(payload)) => self.deliver(&payload),
+                Ok(None) => return,
+                Err(e) => {
+                    self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("connection dropped: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn serve(receiver: Arc<Receiver>) -> Result<()> {
+    let url = &receiver.options.url;
+    let (scheme, addr) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow!("not a url: {}", url))?;
+    if receiver.options.reliable && scheme != "udp" {
+        bail!("--reliable needs a udp url: {}", url);
+    }
+    let mut buf = vec![0u8; 65536];
+    match scheme {
+        "udp" if receiver.options.reliable => {
+            let mut socket = ReliableReceiver::bind(addr)?;
+            eprintln!("listening on {}", socket.socket().local_addr()?);
+            loop {
+                let (payload, _) = socket.recv_from()?;
+                if let Some(stats) = &receiver.stats.reliable {
+                    stats
+                        .duplicates
+                        .store(socket.duplicates(), Ordering::Relaxed);
+                    stats.early.store(socket.early(), Ordering::Relaxed);
+                    stats.lost.store(socket.lost(), Ordering::Relaxed);
+                }
+                receiver.deliver(&payload);
+            }
+        }
+        "udp" => {
+            let socket = UdpSocket::bind(addr)?;
+            eprintln!("listening on {}", socket.local_addr()?);
+            loop {
+                let len = socket.recv(&mut buf)?;
+                receiver.deliver(&buf[..len]);
+            }
+        }
+        "tcp" => {
+            let listener = TcpListener::bind(addr)?;
+            eprintln!("listening on {}", listener.local_addr()?);
+            for conn in listener.incoming() {
+                let conn = conn?;
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || receiver.read_stream(conn));
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        "unix" | "unixgram" => {
+            use std::os::unix::net::{UnixDatagram, UnixListener};
+            use xipin_resolution::sender::UnixAddr;
+
+            let unix_addr = UnixAddr::parse(addr)?;
+            // a socket file left by a previous run
+            if let UnixAddr::Path(path) = &unix_addr {
+                let _ = std::fs::remove_file(path);
+            }
+            let socket_addr = unix_addr.to_socket_addr()?;
+            eprintln!("listening on {}", addr);
+            if scheme == "unixgram" {
+                let socket = UnixDatagram::bind_addr(&socket_addr)?;
+                loop {
+                    let len = socket.recv(&mut buf)?;
+                    receiver.deliver(&buf[..len]);
+                }
+            }
+            let listener = UnixListener::bind_addr(&socket_addr)?;
+            for conn in listener.incoming() {
+                let conn = conn?;
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || receiver.read_stream(conn));
+            }
+            Ok(())
+        }
+        _ => bail!("unsupported url: {}", url),
+    }
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let interval = options.stats;
+    let receiver = match Receiver::new(options) {
+        Ok(receiver) => Arc::new(receiver),
+        Err(e) => {
+            eprintln!("receiver error: {:?}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(interval) = interval {
+        let receiver = Arc::clone(&receiver);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            eprintln!("{}", receiver.stats.report(receiver.start.elapsed()));
+        });
+    }
+    if let Err(e) = serve(Arc::clone(&receiver)) {
+        eprintln!("receiver error: {:?}", e);
+        eprintln!("{}", receiver.stats.report(receiver.start.elapsed()));
+        process::exit(1);
+    }
+}
@@ -0,0 +1,54 @@
+//! CSV records as in RFC 4180, columns
+//! `pattern,source,start,end,timestamp,match` then one per capture group,
+//! empty when the group is unset.
+use std::io::Write;
+
+use super::Record;
+
+fn field(s: &[u8], out: &mut Vec<u8>) {
+    if !s.iter().any(|b| matches!(b, b',' | b'"' | b'\r' | b'\n')) {
+        out.extend_from_slice(s);
+        return;
+    }
+    out.push(b'"');
+    for b in s {
+        if *b == b'"' {
+            out.push(b'"');
+        }
+        out.push(*b);
+    }
+    out.push(b'"');
+}
+
+pub(super) fn encode(record: &Record, out: &mut Vec<u8>) {
+    let _ = write!(out, "{},", record.pattern_id);
+    field(record.source.as_bytes(), out);
+    let _ = write!(
+        out,
+        ",{},{},{},",
+        record.m.start(),
+        record.m.end(),
+        record.unix_millis()
+    );
+    field(record.as_bytes(), out);
+    for capture in &record.captures {
+        out.push(b',');
+        if let Some(m) = capture {
+            field(m.as_bytes(), out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field() {
+        let mut out = Vec::new();
+        field(b"plain", &mut out);
+        out.push(b',');
+        field(b"a,\"b\"\nc", &mut out);
+        assert_eq!(out, b"plain,\"a,\"\"b\"\"\nc\"");
+    }
+}
@@ -0,0 +1,86 @@
+//! JSON Lines, e.g.
+//! `{"pattern":1,"source":"app.log","start":3,"end":7,"match":"2023","captures":["20",null],"timestamp":1682400000123}`
+//! and `"count"` last when the record has one.
+//!
+//! Bytes that aren't valid UTF-8 are replaced with `U+FFFD`.
+use std::io::Write;
+
+use super::Record;
+
+pub(super) fn string(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => out.extend_from_slice(b"\\\""),
+            '\\' => out.extend_from_slice(b"\\\\"),
+            '\n' => out.extend_from_slice(b"\\n"),
+            '\r' => out.extend_from_slice(b"\\r"),
+            '\t' => out.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => {
+                let mut utf8 = [0u8; 4];
+                out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+        }
+    }
+    out.push(b'"');
+}
+
+pub(super) fn encode(record: &Record, out: &mut Vec<u8>) {
+    let _ = write!(out, "{{\"pattern\":{},\"source\":", record.pattern_id);
+    string(record.source.as_bytes(), out);
+    let _ = write!(
+        out,
+        ",\"start\":{},\"end\":{},\"match\":",
+        record.m.start(),
+        record.m.end()
+    );
+    string(record.as_bytes(), out);
+    out.extend_from_slice(b",\"captures\":[");
+    for (i, capture) in record.captures.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        match capture {
+            Some(m) => string(m.as_bytes(), out),
+            None => out.extend_from_slice(b"null"),
+        }
+    }
+    let _ = write!(out, "],\"timestamp\":{}", record.unix_millis());
+    if let Some(count) = record.count {
+        let _ = write!(out, ",\"count\":{}", count);
+    }
+    out.push(b'}');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::tests::record;
+
+    #[test]
+    fn test_json_encode() {
+        let mut out = Vec::new();
+        encode(&record(b"at 2023-04"), &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"pattern":1,"source":"app.log","start":3,"end":7,"match":"2023","captures":["20",null],"timestamp":1682400000123}"#
+        );
+
+        let mut out = Vec::new();
+        encode(&record(b"at 2023-04").with_count(5), &mut out);
+        assert!(out.ends_with(br#""timestamp":1682400000123,"count":5}"#));
+    }
+
+    #[test]
+    fn test_json_escape() {
+        let mut out = Vec::new();
+        string(b"a\"b\\c\nd\x01\xff", &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"a\\\"b\\\\c\\nd\\u0001\u{fffd}\""
+        );
+    }
+}
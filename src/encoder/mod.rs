@@ -0,0 +1,267 @@
+//! Structured encoding of matches, between the matcher and a [`Sender`].
+//!
+//! A [`Record`] carries a match with its offsets, capture groups, pattern id,
+//! source name and timestamp. [`Encoded`] wraps a sender with the [`Format`]
+//! its records are written in, so every sink can pick its own. Records are
+//! encoded without a terminator, the sink's delimiter or framing ends them.
+mod csv;
+mod json;
+mod msgpack;
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::matcher::{Captures, Match};
+use crate::sender::{Sender, Stats};
+
+/// One match, with where it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record<'a> {
+    /// index of the pattern in the pattern list
+    pub pattern_id: usize,
+    /// file name, `-` for stdin, or any label
+    pub source: &'a str,
+    /// the whole match
+    pub m: Match<'a>,
+    /// capture groups from 1 on, `None` if it didn't participate
+    pub captures: Vec<Option<Match<'a>>>,
+    pub timestamp: SystemTime,
+    /// how many duplicates this record stands for, see
+    /// [`crate::sender::Dedup`], only JSON and MessagePack carry it
+    pub count: Option<u64>,
+}
+
+impl<'a> Record<'a> {
+    /// a record of `m` timestamped now, without capture groups
+    pub fn new(pattern_id: usize, source: &'a str, m: Match<'a>) -> Self {
+        Record {
+            pattern_id,
+            source,
+            m,
+            captures: Vec::new(),
+            timestamp: SystemTime::now(),
+            count: None,
+        }
+    }
+
+    /// take the match and capture groups from `captures`
+    pub fn with_captures(mut self, captures: &Captures<'a>) -> Self {
+        if let Some(m) = captures.get(0) {
+            self.m = m;
+        }
+        self.captures = captures.iter().skip(1).collect();
+        self
+    }
+
+    pub fn with_count(mut self, count: u64) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// the matched bytes
+    #[inline]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.m.as_bytes()
+    }
+
+    /// milliseconds since the unix epoch, 0 before it
+    pub fn unix_millis(&self) -> u64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+/// A [`Record`] that owns its bytes, to keep it past the subject or move it
+/// to another thread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnedRecord {
+    pub(crate) pattern_id: usize,
+    pub(crate) source: String,
+    /// the matched bytes, start and end
+    pub(crate) m: (Vec<u8>, usize, usize),
+    pub(crate) captures: Vec<Option<(Vec<u8>, usize, usize)>>,
+    pub(crate) timestamp: SystemTime,
+    pub(crate) count: Option<u64>,
+}
+
+impl From<&Record<'_>> for OwnedRecord {
+    fn from(record: &Record) -> Self {
+        let owned = |m: &Match| (m.as_bytes().to_vec(), m.start(), m.end());
+        OwnedRecord {
+            pattern_id: record.pattern_id,
+            source: record.source.to_string(),
+            m: owned(&record.m),
+            captures: record
+                .captures
+                .iter()
+                .map(|c| c.as_ref().map(owned))
+                .collect(),
+            timestamp: record.timestamp,
+            count: record.count,
+        }
+    }
+}
+
+impl OwnedRecord {
+    pub fn as_record(&self) -> Record<'_> {
+        fn borrowed((bytes, start, end): &(Vec<u8>, usize, usize)) -> Match<'_> {
+            Match::new(bytes, *start, *end)
+        }
+        Record {
+            pattern_id: self.pattern_id,
+            source: &self.source,
+            m: borrowed(&self.m),
+            captures: self
+                .captures
+                .iter()
+                .map(|c| c.as_ref().map(borrowed))
+                .collect(),
+            timestamp: self.timestamp,
+            count: self.count,
+        }
+    }
+}
+
+pub trait Encoder {
+    /// append the encoded `record` to `out`
+    fn encode(&self, record: &Record, out: &mut Vec<u8>) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// the matched bytes only
+    #[default]
+    Raw,
+    /// one JSON object per record
+    JsonLines,
+    /// `pattern,source,start,end,timestamp,match,captures...`
+    Csv,
+    /// one MessagePack map per record, with the same keys as JSON
+    MsgPack,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "raw" => Format::Raw,
+            "json" | "jsonl" => Format::JsonLines,
+            "csv" => Format::Csv,
+            "msgpack" => Format::MsgPack,
+            _ => bail!("unknown format: {}", s),
+        })
+    }
+}
+
+impl Encoder for Format {
+    fn encode(&self, record: &Record, out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Format::Raw => out.extend_from_slice(record.as_bytes()),
+            Format::JsonLines => json::encode(record, out),
+            Format::Csv => csv::encode(record, out),
+            Format::MsgPack => msgpack::encode(record, out),
+        }
+        Ok(())
+    }
+}
+
+impl Format {
+    /// append a received `payload` to `out` as text, MessagePack as JSON
+    pub fn to_text(&self, payload: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Format::MsgPack => msgpack::to_json(payload, out),
+            _ => {
+                out.extend_from_slice(payload);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A sender whose records are encoded with `E` first.
+pub struct Encoded<S, E: Encoder = Format> {
+    sender: S,
+    encoder: E,
+}
+
+impl<S, E: Encoder> Encoded<S, E> {
+    pub fn new(sender: S, encoder: E) -> Self {
+        Self { sender, encoder }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    pub fn encoder(&self) -> &E {
+        &self.encoder
+    }
+}
+
+impl<S: Sender, E: Encoder> Sender for Encoded<S, E> {
+    /// already encoded bytes go through as they are
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.sender.send(buf)
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        let mut out = Vec::with_capacity(record.as_bytes().len() + 64);
+        self.encoder.encode(record, &mut out)?;
+        self.sender.send(&out)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sender::StdoutSink;
+    use std::time::Duration;
+
+    /// `2023` matched in `at 2023-04`, group 1 `20` and an unset group 2
+    pub(crate) fn record(subject: &[u8]) -> Record<'_> {
+        let captures = Captures::new(subject, vec![Some((3, 7)), Some((3, 5)), None]);
+        let mut record = Record::new(1, "app.log", Match::new(b"", 0, 0)).with_captures(&captures);
+        record.timestamp = UNIX_EPOCH + Duration::from_millis(1682400000123);
+        record
+    }
+
+    #[test]
+    fn test_record_with_captures() {
+        let record = record(b"at 2023-04");
+        assert_eq!(record.as_bytes(), b"2023");
+        assert_eq!((record.m.start(), record.m.end()), (3, 7));
+        assert_eq!(record.captures.len(), 2);
+        assert_eq!(record.captures[0].unwrap().as_bytes(), b"20");
+        assert_eq!(record.unix_millis(), 1682400000123);
+    }
+
+    #[test]
+    fn test_encoded_per_sink() {
+        let subject = b"at 2023-04";
+        let raw = Encoded::new(StdoutSink::with_writer(Vec::new()), Format::Raw);
+        let csv = Encoded::new(
+            StdoutSink::with_writer(Vec::new()),
+            "csv".parse::<Format>().unwrap(),
+        );
+        raw.send_record(&record(subject)).unwrap();
+        csv.send_record(&record(subject)).unwrap();
+        assert_eq!(raw.sender.into_inner(), b"2023\n");
+        assert_eq!(
+            csv.sender.into_inner(),
+            b"1,app.log,3,7,1682400000123,2023,20,\n"
+        );
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
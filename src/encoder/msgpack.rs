@@ -0,0 +1,292 @@
+//! MessagePack, a map with the keys of the JSON encoding. The match and the
+//! capture groups are `bin`, since they needn't be UTF-8, unset groups `nil`.
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use super::{json, Record};
+
+fn uint(n: u64, out: &mut Vec<u8>) {
+    match n {
+        0..=0x7f => out.push(n as u8),
+        0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xcf);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// the marker for lengths up to 8, 16 and 32 bits
+fn len(len: usize, markers: [u8; 3], out: &mut Vec<u8>) {
+    if len <= 0xff {
+        out.extend_from_slice(&[markers[0], len as u8]);
+    } else if len <= 0xffff {
+        out.push(markers[1]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn str(s: &str, out: &mut Vec<u8>) {
+    if s.len() < 32 {
+        out.push(0xa0 | s.len() as u8);
+    } else {
+        len(s.len(), [0xd9, 0xda, 0xdb], out);
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn bin(b: &[u8], out: &mut Vec<u8>) {
+    len(b.len(), [0xc4, 0xc5, 0xc6], out);
+    out.extend_from_slice(b);
+}
+
+fn array(n: usize, out: &mut Vec<u8>) {
+    if n < 16 {
+        out.push(0x90 | n as u8);
+    } else if n <= 0xffff {
+        out.push(0xdc);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else {
+        out.push(0xdd);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+}
+
+pub(super) fn encode(record: &Record, out: &mut Vec<u8>) {
+    // fixmap of 7 entries, 8 with a count
+    out.push(0x87 + record.count.is_some() as u8);
+    str("pattern", out);
+    uint(record.pattern_id as u64, out);
+    str("source", out);
+    str(record.source, out);
+    str("start", out);
+    uint(record.m.start() as u64, out);
+    str("end", out);
+    uint(record.m.end() as u64, out);
+    str("match", out);
+    bin(record.as_bytes(), out);
+    str("captures", out);
+    array(record.captures.len(), out);
+    for capture in &record.captures {
+        match capture {
+            Some(m) => bin(m.as_bytes(), out),
+            None => out.push(0xc0),
+        }
+    }
+    str("timestamp", out);
+    uint(record.unix_millis(), out);
+    if let Some(count) = record.count {
+        str("count", out);
+        uint(count, out);
+    }
+}
+
+/// arrays and maps nested deeper than this are refused, so network input
+/// can't recurse the reader off its stack, a record nests 2 deep
+const MAX_DEPTH: usize = 64;
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    /// arrays and maps the reader is in
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("truncated msgpack: {} of {} bytes", self.buf.len(), n);
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    /// a big endian unsigned integer of `n` bytes
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        Ok(self.take(n)?.iter().fold(0, |acc, b| acc << 8 | *b as u64))
+    }
+
+    fn value(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let marker = self.take(1)?[0];
+        let nested = matches!(marker, 0x80..=0x9f | 0xdc..=0xdf);
+        if nested && self.depth == MAX_DEPTH {
+            bail!("msgpack nested deeper than {}", MAX_DEPTH);
+        }
+        self.depth += nested as usize;
+        match marker {
+            0x00..=0x7f => {
+                let _ = write!(out, "{}", marker);
+            }
+            0x80..=0x8f => self.map(marker as usize & 0x0f, out)?,
+            0x90..=0x9f => self.array(marker as usize & 0x0f, out)?,
+            0xa0..=0xbf => json::string(self.take(marker as usize & 0x1f)?, out),
+            0xc0 => out.extend_from_slice(b"null"),
+            0xc2 => out.extend_from_slice(b"false"),
+            0xc3 => out.extend_from_slice(b"true"),
+            // bin and str both end up as JSON strings
+            0xc4..=0xc6 | 0xd9..=0xdb => {
+                let width = match marker {
+                    0xc4..=0xc6 => 1 << (marker - 0xc4),
+                    _ => 1 << (marker - 0xd9),
+                };
+                let n = self.uint(width)? as usize;
+                json::string(self.take(n)?, out);
+            }
+            0xcc..=0xcf => {
+                let n = self.uint(1 << (marker - 0xcc))?;
+                let _ = write!(out, "{}", n);
+            }
+            0xdc | 0xdd => {
+                let n = self.uint(2 << (marker - 0xdc))? as usize;
+                self.array(n, out)?;
+            }
+            0xde | 0xdf => {
+                let n = self.uint(2 << (marker - 0xde))? as usize;
+                self.map(n, out)?;
+            }
+            0xe0..=0xff => {
+                let _ = write!(out, "{}", marker as i8);
+            }
+            _ => bail!("unsupported msgpack marker: {:#04x}", marker),
+        }
+        self.depth -= nested as usize;
+        Ok(())
+    }
+
+    fn array(&mut self, n: usize, out: &mut Vec<u8>) -> Result<()> {
+        out.push(b'[');
+        for i in 0..n {
+            if i > 0 {
+                out.push(b',');
+            }
+            self.value(out)?;
+        }
+        out.push(b']');
+        Ok(())
+    }
+
+    fn map(&mut self, n: usize, out: &mut Vec<u8>) -> Result<()> {
+        out.push(b'{');
+        for i in 0..n {
+            if i > 0 {
+                out.push(b',');
+            }
+            self.value(out)?;
+            out.push(b':');
+            self.value(out)?;
+        }
+        out.push(b'}');
+        Ok(())
+    }
+}
+
+/// one MessagePack value as JSON, for showing what a receiver got
+pub(super) fn to_json(buf: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let mut reader = Reader { buf, depth: 0 };
+    reader.value(out)?;
+    if !reader.buf.is_empty() {
+        bail!("{} bytes after the msgpack value", reader.buf.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::tests::record;
+
+    #[test]
+    fn test_msgpack_uint() {
+        let mut out = Vec::new();
+        for n in [5, 200, 1000, 70000, 1682400000123] {
+            uint(n, &mut out);
+        }
+        assert_eq!(
+            out,
+            [
+                &[0x05][..],
+                &[0xcc, 200],
+                &[0xcd, 0x03, 0xe8],
+                &[0xce, 0, 1, 0x11, 0x70],
+                &[0xcf, 0, 0, 0x01, 0x87, 0xb6, 0xda, 0xb8, 0x7b],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_msgpack_encode() {
+        let mut out = Vec::new();
+        encode(&record(b"at 2023-04"), &mut out);
+        let mut expect = vec![0x87, 0xa7];
+        expect.extend_from_slice(b"pattern");
+        expect.extend_from_slice(&[0x01, 0xa6]);
+        expect.extend_from_slice(b"source");
+        expect.push(0xa7);
+        expect.extend_from_slice(b"app.log");
+        expect.push(0xa5);
+        expect.extend_from_slice(b"start");
+        expect.extend_from_slice(&[0x03, 0xa3]);
+        expect.extend_from_slice(b"end");
+        expect.extend_from_slice(&[0x07, 0xa5]);
+        expect.extend_from_slice(b"match");
+        expect.extend_from_slice(&[0xc4, 0x04]);
+        expect.extend_from_slice(b"2023");
+        expect.push(0xa8);
+        expect.extend_from_slice(b"captures");
+        expect.extend_from_slice(&[0x92, 0xc4, 0x02]);
+        expect.extend_from_slice(b"20");
+        expect.extend_from_slice(&[0xc0, 0xa9]);
+        expect.extend_from_slice(b"timestamp");
+        expect.extend_from_slice(&[0xcf, 0, 0, 0x01, 0x87, 0xb6, 0xda, 0xb8, 0x7b]);
+        assert_eq!(out, expect);
+    }
+
+    #[test]
+    fn test_msgpack_to_json() {
+        let mut packed = Vec::new();
+        for record in [record(b"at 2023-04"), record(b"at 2023-04").with_count(3)] {
+            packed.clear();
+            encode(&record, &mut packed);
+            let mut out = Vec::new();
+            to_json(&packed, &mut out).unwrap();
+            let mut expect = Vec::new();
+            json::encode(&record, &mut expect);
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(expect).unwrap()
+            );
+        }
+
+        assert!(to_json(&packed[..packed.len() - 1], &mut Vec::new()).is_err());
+        assert!(to_json(&[0x01, 0x02], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_msgpack_to_json_depth() {
+        // a datagram of one element arrays
+        let mut nested = vec![0x91; 65536];
+        nested.push(0x01);
+        let e = to_json(&nested, &mut Vec::new()).unwrap_err();
+        assert_eq!(e.to_string(), "msgpack nested deeper than 64");
+
+        let mut nested = vec![0x91; MAX_DEPTH];
+        nested.push(0x01);
+        let mut out = Vec::new();
+        to_json(&nested, &mut out).unwrap();
+        let expect = format!("{}1{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert_eq!(String::from_utf8(out).unwrap(), expect);
+    }
+}
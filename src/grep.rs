@@ -0,0 +1,406 @@
+//! `grep`-style output of a search, the lines that match rather than the
+//! matches.
+//!
+//! [`Lines`] splits a subject at `\n` and numbers the lines, [`Grep`] runs the
+//! matchers over the whole subject, so a pattern may span lines, and prints
+//! every line a match touches, with context, file names, line numbers, byte
+//! offsets and colors as configured.
+
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use anyhow::Result;
+
+use crate::matcher::Matcher;
+
+/// the colors of GNU grep
+const MATCH: &[u8] = b"\x1b[01;31m\x1b[K";
+const FILE: &[u8] = b"\x1b[35m\x1b[K";
+const NUMBER: &[u8] = b"\x1b[32m\x1b[K";
+const SEPARATOR: &[u8] = b"\x1b[36m\x1b[K";
+const RESET: &[u8] = b"\x1b[m\x1b[K";
+
+/// A line of a subject, without its `\n`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    /// from 1
+    pub number: u64,
+    /// byte offsets in the subject
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The lines of a subject, a last one without `\n` too.
+pub struct Lines<'s> {
+    subject: &'s [u8],
+    pos: usize,
+    number: u64,
+}
+
+impl<'s> Lines<'s> {
+    pub fn new(subject: &'s [u8]) -> Self {
+        Lines {
+            subject,
+            pos: 0,
+            number: 0,
+        }
+    }
+}
+
+impl Iterator for Lines<'_> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        if self.pos >= self.subject.len() {
+            return None;
+        }
+        let start = self.pos;
+        let end = self.subject[start..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(self.subject.len(), |i| start + i);
+        self.pos = end + 1;
+        self.number += 1;
+        Some(Line {
+            number: self.number,
+            start,
+            end,
+        })
+    }
+}
+
+/// the matches of all `matchers`, leftmost first, the longest of those
+/// starting together, without overlaps
+fn matches<M: Matcher>(matchers: &[M], subject: &[u8]) -> Result<Vec<Range<usize>>> {
+    let mut all = Vec::new();
+    for re in matchers {
+        for m in re.find_iter(subject) {
+            let m = m?;
+            all.push(m.start()..m.end());
+        }
+    }
+    all.sort_by_key(|m| (m.start, Reverse(m.end)));
+    let mut matches: Vec<Range<usize>> = Vec::with_capacity(all.len());
+    for m in all {
+        if matches.last().is_none_or(|last| m.start >= last.end) {
+            matches.push(m);
+        }
+    }
+    Ok(matches)
+}
+
+/// What a search prints, the matching lines by default.
+#[derive(Clone, Debug, Default)]
+pub struct Grep {
+    with_filename: bool,
+    line_number: bool,
+    byte_offset: bool,
+    only_matching: bool,
+    count: bool,
+    files_with_matches: bool,
+    invert: bool,
+    before: usize,
+    after: usize,
+    color: bool,
+}
+
+impl Grep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// prefix lines with the source
+    pub fn with_filename(mut self, yes: bool) -> Self {
+        self.with_filename = yes;
+        self
+    }
+
+    /// prefix lines with their number, from 1
+    pub fn line_number(mut self, yes: bool) -> Self {
+        self.line_number = yes;
+        self
+    }
+
+    /// prefix lines with the offset of their first byte, or of the match
+    /// with [`Grep::only_matching`]
+    pub fn byte_offset(mut self, yes: bool) -> Self {
+        self.byte_offset = yes;
+        self
+    }
+
+    /// print the matches, one per line, instead of their lines
+    pub fn only_matching(mut self, yes: bool) -> Self {
+        self.only_matching = yes;
+        self
+    }
+
+    /// print the number of selected lines only
+    pub fn count(mut self, yes: bool) -> Self {
+        self.count = yes;
+        self
+    }
+
+    /// print the source only, if a line is selected
+    pub fn files_with_matches(mut self, yes: bool) -> Self {
+        self.files_with_matches = yes;
+        self
+    }
+
+    /// select the lines without a match
+    pub fn invert(mut self, yes: bool) -> Self {
+        self.invert = yes;
+        self
+    }
+
+    /// lines printed before and after every selected one, groups of lines
+    /// apart are separated by `--`
+    pub fn context(mut self, before: usize, after: usize) -> Self {
+        self.before = before;
+        self.after = after;
+        self
+    }
+
+    /// highlight matches, file names, numbers and separators
+    pub fn color(mut self, yes: bool) -> Self {
+        self.color = yes;
+        self
+    }
+
+    /// print the selected lines of `subject` to `out`, returns how many
+    /// there were
+    pub fn search<M: Matcher, W: Write>(
+        &self,
+        matchers: &[M],
+        source: &str,
+        subject: &[u8],
+        out: &mut W,
+    ) -> Result<u64> {
+        let matches = matches(matchers, subject)?;
+        let mut printer = Printer {
+            grep: self,
+            source,
+            subject,
+            out,
+            last: None,
+            before: VecDeque::new(),
+            after: 0,
+        };
+        let quiet = self.count || self.files_with_matches;
+        let mut selected = 0;
+        // the first match that doesn't end before the line
+        let mut next = 0;
+        for line in Lines::new(subject) {
+            while matches
+                .get(next)
+                .is_some_and(|m| m.end < line.start || (m.end == line.start && m.start < m.end))
+            {
+                next += 1;
+            }
+            let on_line = matches[next..]
+                .iter()
+                .take_while(|m| m.start <= line.end)
+                .count();
+            let on_line = &matches[next..next + on_line];
+            if on_line.is_empty() == self.invert {
+                selected += 1;
+                if self.files_with_matches {
+                    break;
+                }
+                if !quiet {
+                    printer.selected(&line, on_line)?;
+                }
+            } else if !quiet {
+                printer.context(&line)?;
+            }
+        }
+        if self.files_with_matches {
+            if selected > 0 {
+                printer.colored(FILE, source.as_bytes())?;
+                printer.out.write_all(b"\n")?;
+            }
+        } else if self.count {
+            if self.with_filename {
+                printer.colored(FILE, source.as_bytes())?;
+                printer.colored(SEPARATOR, b":")?;
+            }
+            writeln!(printer.out, "{}", selected)?;
+        }
+        Ok(selected)
+    }
+}
+
+struct Printer<'a, W> {
+    grep: &'a Grep,
+    source: &'a str,
+    subject: &'a [u8],
+    out: &'a mut W,
+    /// number of the last line printed
+    last: Option<u64>,
+    /// lines to print if one after them is selected
+    before: VecDeque<Line>,
+    /// lines still to print after a selected one
+    after: usize,
+}
+
+impl<W: Write> Printer<'_, W> {
+    fn selected(&mut self, line: &Line, matches: &[Range<usize>]) -> io::Result<()> {
+        if self.grep.only_matching {
+            // a match spanning lines is printed with its first
+            for m in matches {
+                if m.start < line.start || m.start == m.end {
+                    continue;
+                }
+                self.prefix(line.number, m.start, b':')?;
+                self.colored(MATCH, &self.subject[m.clone()])?;
+                self.out.write_all(b"\n")?;
+            }
+            return Ok(());
+        }
+        while let Some(before) = self.before.pop_front() {
+            self.line(&before, b'-', &[])?;
+        }
+        self.after = self.grep.after;
+        self.line(line, b':', matches)
+    }
+
+    fn context(&mut self, line: &Line) -> io::Result<()> {
+        if self.grep.only_matching {
+            return Ok(());
+        }
+        if self.after > 0 {
+            self.after -= 1;
+            return self.line(line, b'-', &[]);
+        }
+        if self.grep.before > 0 {
+            if self.before.len() == self.grep.before {
+                self.before.pop_front();
+            }
+            self.before.push_back(line.clone());
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &Line, separator: u8, matches: &[Range<usize>]) -> io::Result<()> {
+        let context = self.grep.before > 0 || self.grep.after > 0;
+        if context && self.last.is_some_and(|last| last + 1 < line.number) {
+            self.colored(SEPARATOR, b"--")?;
+            self.out.write_all(b"\n")?;
+        }
+        self.last = Some(line.number);
+        self.prefix(line.number, line.start, separator)?;
+        let mut pos = line.start;
+        for m in matches {
+            let (start, end) = (m.start.max(line.start), m.end.min(line.end));
+            if start >= end {
+                continue;
+            }
+            self.out.write_all(&self.subject[pos..start])?;
+            self.colored(MATCH, &self.subject[start..end])?;
+            pos = end;
+        }
+        self.out.write_all(&self.subject[pos..line.end])?;
+        self.out.write_all(b"\n")
+    }
+
+    /// `file:number:offset:` as configured, `-` after context lines
+    fn prefix(&mut self, number: u64, offset: usize, separator: u8) -> io::Result<()> {
+        if self.grep.with_filename {
+            self.colored(FILE, self.source.as_bytes())?;
+            self.colored(SEPARATOR, &[separator])?;
+        }
+        if self.grep.line_number {
+            self.colored(NUMBER, number.to_string().as_bytes())?;
+            self.colored(SEPARATOR, &[separator])?;
+        }
+        if self.grep.byte_offset {
+            self.colored(NUMBER, offset.to_string().as_bytes())?;
+            self.colored(SEPARATOR, &[separator])?;
+        }
+        Ok(())
+    }
+
+    fn colored(&mut self, color: &[u8], text: &[u8]) -> io::Result<()> {
+        if !self.grep.color {
+            return self.out.write_all(text);
+        }
+        self.out.write_all(color)?;
+        self.out.write_all(text)?;
+        self.out.write_all(RESET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::PCRE2;
+
+    const LOG: &[u8] = b"boot\n2023 disk full\nok\nok\n2024 disk full\nok\nok\nok\nend";
+
+    fn grep(grep: Grep, patterns: &[&str], subject: &[u8]) -> String {
+        let matchers: Vec<_> = patterns.iter().map(|p| PCRE2::new(p).unwrap()).collect();
+        let mut out = Vec::new();
+        grep.search(&matchers, "app.log", subject, &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        let lines: Vec<_> = Lines::new(b"a\n\nbc")
+            .map(|l| (l.number, l.start, l.end))
+            .collect();
+        assert_eq!(lines, [(1, 0, 1), (2, 2, 2), (3, 3, 5)]);
+        assert_eq!(Lines::new(b"a\n").count(), 1);
+        assert_eq!(Lines::new(b"").count(), 0);
+    }
+
+    #[test]
+    fn test_grep_lines() {
+        let out = grep(Grep::new().line_number(true), &[r"\d{4}"], LOG);
+        assert_eq!(out, "2:2023 disk full\n5:2024 disk full\n");
+        let out = grep(
+            Grep::new().byte_offset(true).only_matching(true),
+            &["disk", r"\d+"],
+            LOG,
+        );
+        assert_eq!(out, "5:2023\n10:disk\n26:2024\n31:disk\n");
+        let out = grep(
+            Grep::new().invert(true).count(true).with_filename(true),
+            &["ok"],
+            LOG,
+        );
+        assert_eq!(out, "app.log:4\n");
+        assert_eq!(
+            grep(Grep::new().files_with_matches(true), &["end"], LOG),
+            "app.log\n"
+        );
+        assert_eq!(
+            grep(Grep::new().files_with_matches(true), &["none"], LOG),
+            ""
+        );
+        // a match over lines selects all of them
+        assert_eq!(
+            grep(Grep::new(), &[r"full\nok"], LOG),
+            "2023 disk full\nok\n2024 disk full\nok\n"
+        );
+        let out = grep(Grep::new().color(true), &["disk"], b"a disk\n");
+        assert_eq!(out, "a \x1b[01;31m\x1b[Kdisk\x1b[m\x1b[K\n");
+    }
+
+    #[test]
+    fn test_grep_context() {
+        let out = grep(
+            Grep::new().line_number(true).context(1, 1),
+            &[r"\d{4}"],
+            LOG,
+        );
+        assert_eq!(
+            out,
+            "1-boot\n2:2023 disk full\n3-ok\n4-ok\n5:2024 disk full\n6-ok\n"
+        );
+        let out = grep(Grep::new().context(0, 2), &["boot", "end"], LOG);
+        assert_eq!(out, "boot\n2023 disk full\nok\n--\nend\n");
+    }
+}
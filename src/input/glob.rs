@@ -0,0 +1,319 @@
+//! Shell globs, and the `.gitignore` rules built on them.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+/// names of the ignore files read in every directory, later ones win
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Literal(u8),
+    /// `?`
+    Any,
+    /// `*`, within a path component
+    Star,
+    /// `**/`, any number of whole components
+    Dirs,
+    /// `/**` at the end, anything below
+    Rest,
+    /// `[a-z]` and `[!a-z]`
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+/// A shell glob over `/` separated paths.
+///
+/// `*` and `?` don't match a `/`, `**/` matches any number of directories and
+/// a trailing `/**` everything below, `[...]` is a class, `[!...]` or `[^...]`
+/// its negation, and `\` escapes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Glob {
+    glob: String,
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    pub fn new(glob: &str) -> Result<Self> {
+        let bytes = glob.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let at_start = i == 0 || bytes[i - 1] == b'/';
+            match bytes[i] {
+                b'*' if bytes[i..].starts_with(b"**/") && at_start => {
+                    tokens.push(Token::Dirs);
+                    i += 3;
+                    continue;
+                }
+                b'/' if &bytes[i..] == b"/**" => {
+                    tokens.push(Token::Rest);
+                    break;
+                }
+                // anything at all
+                b'*' if bytes == b"**" => {
+                    tokens.extend([Token::Dirs, Token::Star]);
+                    break;
+                }
+                b'*' => tokens.push(Token::Star),
+                b'?' => tokens.push(Token::Any),
+                b'[' => {
+                    let (class, len) = Glob::class(&bytes[i..])
+                        .ok_or_else(|| anyhow!("unclosed [ in glob: {}", glob))?;
+                    tokens.push(class);
+                    i += len;
+                    continue;
+                }
+                b'\\' => match bytes.get(i + 1) {
+                    Some(c) => {
+                        tokens.push(Token::Literal(*c));
+                        i += 1;
+                    }
+                    None => bail!("trailing \\ in glob: {}", glob),
+                },
+                c => tokens.push(Token::Literal(c)),
+            }
+            i += 1;
+        }
+        // `a*` and `a**` are the same
+        tokens.dedup_by(|b, a| *a == Token::Star && *b == Token::Star);
+        Ok(Glob {
+            glob: glob.to_string(),
+            tokens,
+        })
+    }
+
+    /// the class at the start of `glob` and its length
+    fn class(glob: &[u8]) -> Option<(Token, usize)> {
+        let mut i = 1;
+        let negated = matches!(glob.get(i), Some(b'!' | b'^'));
+        if negated {
+            i += 1;
+        }
+        let mut ranges = Vec::new();
+        // a `]` first is a literal
+        let first = i;
+        loop {
+            let c = match *glob.get(i)? {
+                b']' if i > first => return Some((Token::Class { negated, ranges }, i + 1)),
+                b'\\' => {
+                    i += 1;
+                    *glob.get(i)?
+                }
+                c => c,
+            };
+            match (glob.get(i + 1), glob.get(i + 2)) {
+                (Some(b'-'), Some(&end)) if end != b']' => {
+                    ranges.push((c, end));
+                    i += 3;
+                }
+                _ => {
+                    ranges.push((c, c));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.glob
+    }
+
+    /// whether the whole of `path` matches
+    pub fn is_match(&self, path: &[u8]) -> bool {
+        matches(&self.tokens, path)
+    }
+}
+
+// Iterative, with one point to go back to for the last `*` and one for the
+// last `**/`: a later `*` can take whatever an earlier one in the same
+// component would, and a later `**/` whatever an earlier one would, so
+// neither needs more than one, and the time is linear in `path` per token.
+fn matches(tokens: &[Token], path: &[u8]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    // the token after the `*` or `**/` and where in `path` it's tried next
+    let mut star: Option<(usize, usize)> = None;
+    let mut dirs: Option<(usize, usize)> = None;
+    loop {
+        let matched = match (tokens.get(t), path.get(p)) {
+            (Some(Token::Star), _) => {
+                star = Some((t + 1, p));
+                t += 1;
+                continue;
+            }
+            (Some(Token::Dirs), _) => {
+                dirs = Some((t + 1, p));
+                star = None;
+                t += 1;
+                continue;
+            }
+            (Some(Token::Rest), Some(b'/')) | (None, None) => return true,
+            (Some(Token::Literal(l)), Some(c)) => l == c,
+            (Some(Token::Any), Some(c)) => *c != b'/',
+            (Some(Token::Class { negated, ranges }), Some(c)) => {
+                *c != b'/' && ranges.iter().any(|(lo, hi)| (lo..=hi).contains(&c)) != *negated
+            }
+            (Some(Token::Rest), _) | (Some(_), None) | (None, Some(_)) => false,
+        };
+        if matched {
+            t += 1;
+            p += 1;
+            continue;
+        }
+        // one more byte for the last `*`, within its component
+        if let Some((st, sp)) = star {
+            if path.get(sp).is_some_and(|c| *c != b'/') {
+                star = Some((st, sp + 1));
+                (t, p) = (st, sp + 1);
+                continue;
+            }
+        }
+        // one more component for the last `**/`
+        if let Some((dt, dp)) = dirs {
+            if let Some(slash) = path[dp..].iter().position(|c| *c == b'/') {
+                dirs = Some((dt, dp + slash + 1));
+                star = None;
+                (t, p) = (dt, dp + slash + 1);
+                continue;
+            }
+        }
+        return false;
+    }
+}
+
+/// One line of an ignore file.
+#[derive(Clone, Debug)]
+struct Rule {
+    glob: Glob,
+    /// `!`, whitelists what an earlier rule ignores
+    negated: bool,
+    /// a trailing `/`
+    dir_only: bool,
+}
+
+/// The rules of the ignore files of one directory, in the `.gitignore`
+/// syntax.
+///
+/// A rule without a `/` but at its end matches a name at any depth, one with
+/// a `/` a path relative to the directory. A trailing `/` only matches
+/// directories and `!` whitelists again. Lines that aren't a valid glob are
+/// skipped, as git does.
+#[derive(Clone, Debug, Default)]
+pub struct Ignore {
+    rules: Vec<Rule>,
+}
+
+impl Ignore {
+    pub fn parse(text: &str) -> Self {
+        let rules = text.lines().filter_map(|line| {
+            let line = line.trim_end_matches(['\r', ' ']);
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let glob = match line.strip_prefix('/') {
+                Some(anchored) => Glob::new(anchored),
+                None if line.contains('/') => Glob::new(line),
+                None => Glob::new(&format!("**/{}", line)),
+            };
+            Some(Rule {
+                glob: glob.ok()?,
+                negated,
+                dir_only,
+            })
+        });
+        Ignore {
+            rules: rules.collect(),
+        }
+    }
+
+    /// the rules of the ignore files in `dir`, `None` without any
+    pub fn from_dir(dir: &Path) -> Option<Self> {
+        let mut ignore = Ignore::default();
+        for name in IGNORE_FILES {
+            if let Ok(text) = fs::read_to_string(dir.join(name)) {
+                ignore.rules.extend(Ignore::parse(&text).rules);
+            }
+        }
+        Some(ignore).filter(|ignore| !ignore.rules.is_empty())
+    }
+
+    /// `Some(true)` if `path`, relative to the directory, is ignored,
+    /// `Some(false)` if it's whitelisted, `None` if no rule is about it
+    pub fn matched(&self, path: &[u8], is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.glob.is_match(path))
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(glob: &str, path: &str) -> bool {
+        Glob::new(glob).unwrap().is_match(path.as_bytes())
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(is_match("*.rs", "main.rs"));
+        assert!(!is_match("*.rs", "src/main.rs"));
+        assert!(is_match("src/*.rs", "src/main.rs"));
+        assert!(is_match("**/*.rs", "main.rs"));
+        assert!(is_match("**/*.rs", "src/sender/udp.rs"));
+        assert!(is_match("src/**/udp.rs", "src/udp.rs"));
+        assert!(is_match("src/**/udp.rs", "src/sender/udp.rs"));
+        assert!(is_match("target/**", "target/debug/x"));
+        assert!(!is_match("target/**", "target"));
+        assert!(is_match("**", "a/b"));
+        assert!(is_match("?.log", "a.log"));
+        assert!(!is_match("?.log", "ab.log"));
+        assert!(is_match("[a-c]x[!0-9]", "bxy"));
+        assert!(!is_match("[a-c]x[!0-9]", "bx1"));
+        assert!(is_match("[]]", "]"));
+        assert!(is_match(r"\*", "*"));
+        assert!(!is_match(r"\*", "a"));
+        assert!(Glob::new("[a-").is_err());
+    }
+
+    #[test]
+    fn test_glob_backtracking() {
+        // exponential for a naive backtracking matcher
+        let glob = Glob::new("*a*a*a*a*a*a*a*a*b").unwrap();
+        assert!(!glob.is_match(&[b'a'; 60]));
+        assert!(glob.is_match(&[&[b'a'; 60][..], b"b"].concat()));
+        let glob = Glob::new("**/a*/**/a*/**/a*/**/b").unwrap();
+        assert!(!glob.is_match("a/".repeat(30).as_bytes()));
+        assert!(glob.is_match(format!("{}b", "a/".repeat(30)).as_bytes()));
+        assert!(!is_match("*x/*y", "ax/bx/cy"));
+        assert!(is_match("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn test_ignore() {
+        let ignore = Ignore::parse("# build\ntarget/\n*.log\n!keep.log\n/Cargo.lock\ndocs/*.md\n");
+        assert_eq!(ignore.matched(b"target", true), Some(true));
+        assert_eq!(ignore.matched(b"target", false), None);
+        assert_eq!(ignore.matched(b"a/b/app.log", false), Some(true));
+        assert_eq!(ignore.matched(b"a/keep.log", false), Some(false));
+        assert_eq!(ignore.matched(b"Cargo.lock", false), Some(true));
+        assert_eq!(ignore.matched(b"sub/Cargo.lock", false), None);
+        assert_eq!(ignore.matched(b"docs/a.md", false), Some(true));
+        assert_eq!(ignore.matched(b"docs/api/a.md", false), None);
+        assert_eq!(ignore.matched(b"main.rs", false), None);
+    }
+}
@@ -0,0 +1,153 @@
+//! Where the subjects of a search come from.
+//!
+//! [`Walk`] finds the files under directories, filtered by `.gitignore`-style
+//! [`Ignore`] files and [`Glob`]s, [`Subject::open`] reads one, memory-mapped
+//! if it's large, and [`is_binary`] tells the files not worth searching.
+mod glob;
+mod walk;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+pub use glob::*;
+pub use walk::*;
+
+/// files of this size and up are mapped rather than read
+pub const MMAP_THRESHOLD: u64 = 1 << 20;
+
+/// bytes looked at for a NUL, as git does
+const BINARY_PEEK: usize = 8000;
+
+/// whether `subject` looks binary, a NUL byte near its start
+pub fn is_binary(subject: &[u8]) -> bool {
+    subject[..subject.len().min(BINARY_PEEK)].contains(&0)
+}
+
+/// The bytes of a file.
+pub enum Subject {
+    Bytes(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(Mmap),
+}
+
+impl Subject {
+    /// read `path`, `-` for stdin, files of [`MMAP_THRESHOLD`] and more are
+    /// mapped on unix
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut buf = Vec::new();
+        if path == Path::new("-") {
+            io::stdin().read_to_end(&mut buf)?;
+            return Ok(Subject::Bytes(buf));
+        }
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        #[cfg(unix)]
+        if len >= MMAP_THRESHOLD {
+            let map = Mmap::new(&file, len as usize).context("mmap")?;
+            return Ok(Subject::Mapped(map));
+        }
+        buf.reserve(len as usize);
+        file.read_to_end(&mut buf)?;
+        Ok(Subject::Bytes(buf))
+    }
+}
+
+impl Deref for Subject {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Subject::Bytes(buf) => buf,
+            #[cfg(unix)]
+            Subject::Mapped(map) => map,
+        }
+    }
+}
+
+/// A read-only private mapping of a whole file.
+///
+/// The file shrinking while mapped makes reading past its new end fail with
+/// `SIGBUS`, as with any mapping.
+#[cfg(unix)]
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned
+#[cfg(unix)]
+unsafe impl Send for Mmap {}
+#[cfg(unix)]
+unsafe impl Sync for Mmap {}
+
+#[cfg(unix)]
+impl Mmap {
+    /// map the first `len` bytes of `file`, more than 0
+    pub fn new(file: &File, len: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // searches read it front to back
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { ptr, len })
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `len` bytes are mapped readable until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject_open() {
+        let path = std::env::temp_dir().join(format!("xipin-subject-{}", std::process::id()));
+        let mut data = b"2023 disk full\n".repeat(MMAP_THRESHOLD as usize / 15 + 1);
+        std::fs::write(&path, &data).unwrap();
+        let subject = Subject::open(&path).unwrap();
+        #[cfg(unix)]
+        assert!(matches!(subject, Subject::Mapped(_)));
+        assert_eq!(&subject[..], &data[..]);
+        assert!(!is_binary(&subject));
+
+        data.truncate(100);
+        data[50] = 0;
+        std::fs::write(&path, &data).unwrap();
+        let subject = Subject::open(&path).unwrap();
+        assert!(matches!(subject, Subject::Bytes(_)));
+        assert!(is_binary(&subject));
+        std::fs::remove_file(&path).unwrap();
+        assert!(Subject::open(&path).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! A recursive directory walk, filtered by ignore files and globs.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::{Glob, Ignore};
+
+/// Which files under a directory are searched.
+///
+/// The files given are always searched, the ones found in directories are
+/// filtered by the ignore files on the way down to them, skipping `.git`,
+/// then by the `exclude` globs and the `include` globs, if there are any. A
+/// glob with a `/` matches the path below the directory given, one without
+/// the name. Symbolic links to directories aren't followed.
+#[derive(Clone, Debug)]
+pub struct Walk {
+    ignore_files: bool,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl Default for Walk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Walk {
+    /// ignore files read, no globs
+    pub fn new() -> Self {
+        Walk {
+            ignore_files: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    /// read `.gitignore` and `.ignore` in every directory, on by default
+    pub fn ignore_files(mut self, yes: bool) -> Self {
+        self.ignore_files = yes;
+        self
+    }
+
+    /// search only the files matching one of the include globs
+    pub fn include(mut self, glob: Glob) -> Self {
+        self.include.push(glob);
+        self
+    }
+
+    /// skip the files and directories matching `glob`
+    pub fn exclude(mut self, glob: Glob) -> Self {
+        self.exclude.push(glob);
+        self
+    }
+
+    /// the files under `paths`, by name in every directory
+    pub fn files<I: IntoIterator<Item = PathBuf>>(&self, paths: I) -> Files<'_> {
+        Files {
+            walk: self,
+            roots: paths.into_iter().collect(),
+            stack: Vec::new(),
+        }
+    }
+}
+
+/// A directory being walked.
+struct Dir {
+    path: PathBuf,
+    /// the rest of the entries and whether they're directories
+    entries: std::vec::IntoIter<(PathBuf, bool)>,
+    ignore: Option<Ignore>,
+}
+
+/// The iterator of [`Walk::files`], a directory that can't be read is an
+/// `Err` and skipped.
+pub struct Files<'w> {
+    walk: &'w Walk,
+    roots: VecDeque<PathBuf>,
+    /// the directories from the root down to the one being walked
+    stack: Vec<Dir>,
+}
+
+impl Files<'_> {
+    fn open(&self, path: PathBuf) -> Result<Dir> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path).with_context(|| format!("{}", path.display()))? {
+            let entry = entry.with_context(|| format!("{}", path.display()))?;
+            let file_type = entry.file_type()?;
+            let is_dir = file_type.is_dir();
+            // a link to a directory could loop
+            if file_type.is_symlink() && entry.path().is_dir() {
+                continue;
+            }
+            entries.push((entry.path(), is_dir));
+        }
+        entries.sort();
+        let ignore = match self.walk.ignore_files {
+            true => Ignore::from_dir(&path),
+            false => None,
+        };
+        Ok(Dir {
+            path,
+            entries: entries.into_iter(),
+            ignore,
+        })
+    }
+
+    /// whether the entry `path` of the innermost directory is filtered out
+    fn skipped(&self, path: &Path, is_dir: bool) -> bool {
+        let name = path.file_name().unwrap_or_default();
+        if self.walk.ignore_files {
+            if is_dir && name == ".git" {
+                return true;
+            }
+            let ignored = self.stack.iter().rev().find_map(|dir| {
+                let ignore = dir.ignore.as_ref()?;
+                let relative = path.strip_prefix(&dir.path).ok()?;
+                ignore.matched(relative.as_os_str().as_encoded_bytes(), is_dir)
+            });
+            if ignored == Some(true) {
+                return true;
+            }
+        }
+        let relative = self
+            .stack
+            .first()
+            .and_then(|root| path.strip_prefix(&root.path).ok())
+            .unwrap_or(path);
+        let is_match = |glob: &Glob| match glob.as_str().contains('/') {
+            true => glob.is_match(relative.as_os_str().as_encoded_bytes()),
+            false => glob.is_match(name.as_encoded_bytes()),
+        };
+        if self.walk.exclude.iter().any(is_match) {
+            return true;
+        }
+        !is_dir && !self.walk.include.is_empty() && !self.walk.include.iter().any(is_match)
+    }
+}
+
+impl Iterator for Files<'_> {
+    type Item = Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Result<PathBuf>> {
+        loop {
+            let Some(dir) = self.stack.last_mut() else {
+                let root = self.roots.pop_front()?;
+                if !root.is_dir() {
+                    return Some(Ok(root));
+                }
+                match self.open(root) {
+                    Ok(dir) => self.stack.push(dir),
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            };
+            let Some((path, is_dir)) = dir.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if self.skipped(&path, is_dir) {
+                continue;
+            }
+            if !is_dir {
+                return Some(Ok(path));
+            }
+            match self.open(path) {
+                Ok(dir) => self.stack.push(dir),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk() {
+        let root = std::env::temp_dir().join(format!("xipin-walk-{}", std::process::id()));
+        for dir in ["src/sender", "target/debug", ".git", "logs"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "README.md",
+            "src/main.rs",
+            "src/sender/udp.rs",
+            "target/debug/main",
+            ".git/HEAD",
+            "logs/a.log",
+            "logs/keep.log",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(root.join("logs/.gitignore"), "*.log\n!keep.log\n").unwrap();
+
+        let names = |walk: &Walk| -> Vec<String> {
+            walk.files([root.clone()])
+                .map(|path| {
+                    let path = path.unwrap();
+                    let path = path.strip_prefix(&root).unwrap();
+                    path.to_string_lossy().replace('\\', "/")
+                })
+                .collect()
+        };
+        assert_eq!(
+            names(&Walk::new()),
+            [
+                ".gitignore",
+                "README.md",
+                "logs/.gitignore",
+                "logs/keep.log",
+                "src/main.rs",
+                "src/sender/udp.rs"
+            ]
+        );
+        let walk = Walk::new()
+            .include(Glob::new("*.rs").unwrap())
+            .exclude(Glob::new("src/sender").unwrap());
+        assert_eq!(names(&walk), ["src/main.rs"]);
+        let walk = Walk::new()
+            .ignore_files(false)
+            .exclude(Glob::new(".*").unwrap());
+        assert_eq!(names(&walk).len(), 6);
+
+        // files given are searched whatever the globs
+        let main = root.join("src/main.rs");
+        let walk = Walk::new().include(Glob::new("*.md").unwrap());
+        assert_eq!(walk.files([main.clone()]).next().unwrap().unwrap(), main);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
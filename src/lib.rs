@@ -0,0 +1,6 @@
+//! `PCRE2` matching with a manual FFI binding, and senders to ship the results.
+pub mod encoder;
+pub mod grep;
+pub mod input;
+pub mod matcher;
+pub mod sender;
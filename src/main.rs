@@ -1,3 +1,10 @@
+//! Searches files or stdin for patterns and prints the matching lines like
+//! `grep -P`, or sends every match to a destination, see `--help`. The target
+//! text below is found with
+//! ```bash
+//! echo '<target text>' | xipin-resolution -d udp://127.0.0.1:7878 '(?<=\d{4})[^\d\s]{3,11}(?=\S)'
+//! ```
+//!
 //! #Requirements:
 //! 1. Call [pcre2](https://github.com/PhilipHazel/pcre2) bind the FFI manually without 3rd lib.
 //! 2. Filter out the **result string** that meets the **filtering rules** from the **target text**.
@@ -14,30 +21,500 @@
 //! 2. The string adjacent to the right of result string is not empty.
 //! 3. Fewer matches is better, use only regular expression as much as possible.
 //!
-mod matcher;
-mod sender;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use pcre2_sys::{
+    PCRE2_CASELESS, PCRE2_DOTALL, PCRE2_EXTENDED, PCRE2_MULTILINE, PCRE2_UCP, PCRE2_UTF,
+};
+use xipin_resolution::encoder::{Encoded, Format, Record};
+use xipin_resolution::grep::Grep;
+use xipin_resolution::input::{is_binary, Glob, Subject, Walk};
+use xipin_resolution::matcher::{Matcher, PCRE2Builder, PCRE2};
+use xipin_resolution::sender::{FileSink, Overflow, Pipeline, Sender, StdoutSink, Tcp, Udp};
+
+const USAGE: &str = "usage: xipin-resolution [options] PATTERN [PATH...]
+       xipin-resolution [options] -e PATTERN... [PATH...]
+       xipin-resolution [options] -f FILE [PATH...]
+
+  PATH                files or directories to search, stdin if none or -,
+                      directories without the files their .gitignore and
+                      .ignore files name, binary files, with a NUL byte near
+                      their start, are skipped
+
+  -e, --regexp PATTERN  a pattern, may be given more than once
+  -f, --file FILE     the patterns in FILE, one per line, blank lines skipped
+  -i                  caseless
+  -m                  multiline, ^ and $ match at every line
+  -s                  dotall, . matches a newline too
+  -x                  extended, whitespace and # comments are ignored
+  -u                  UTF-8 subjects and Unicode properties for \\w, \\d, ...
+
+  --include GLOB      search only the files in directories matching GLOB
+  --exclude GLOB      skip the files and directories matching GLOB, a GLOB
+                      with a / matches the path below the directory given,
+                      one without the name
+  --no-ignore         don't read .gitignore and .ignore files
+  -a, --text          search binary files too
+  -j, --threads N     files searched at once, one per CPU by default
+
+  -d, --dest URL      send the matches to udp://host:port, tcp://host:port,
+                      unix://path, unixgram://path, file://path or - for
+                      stdout
+  --format FORMAT     send the matches as raw, json, csv or msgpack, raw by
+                      default
+  --stats             report what was sent on stderr
+
+Without -d or --format the matching lines are printed, like grep:
+  -H, --with-filename   prefix lines with the file name, the default for
+                      more than one file
+  -h, --no-filename   never prefix lines with the file name
+  -n, --line-number   prefix lines with their number
+  -b, --byte-offset   prefix lines with their offset, of the match with -o
+  -o, --only-matching print the matches only, one per line
+  -c, --count         print the number of matching lines per file
+  -l, --files-with-matches  print the names of the files that match
+  -v, --invert-match  select the lines that don't match
+  -A, --after-context N     print N lines after a matching one
+  -B, --before-context N    print N lines before a matching one
+  -C, --context N     print N lines before and after a matching one
+  --color[=WHEN]      highlight matches, always, never or auto, on a
+                      terminal, the default of --color alone
+  --help              show this
 
-use matcher::PCRE2;
-use sender::{Sender, Udp};
+Exits with 0 if something matched, 1 if nothing did and 2 on an error.";
+
+/// short and long options followed by a value
+fn takes_value(option: &str) -> bool {
+    matches!(
+        option,
+        "-e" | "--regexp"
+            | "-f"
+            | "--file"
+            | "-d"
+            | "--dest"
+            | "--format"
+            | "-A"
+            | "--after-context"
+            | "-B"
+            | "--before-context"
+            | "-C"
+            | "--context"
+            | "--include"
+            | "--exclude"
+            | "-j"
+            | "--threads"
+    )
+}
+
+/// `-ix` as `-i -x`, `-efoo` as `-e foo`
+fn expand<I: Iterator<Item = String>>(mut args: I) -> Vec<String> {
+    let mut out = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            out.push(arg);
+            out.extend(args);
+            break;
+        }
+        if takes_value(&arg) {
+            out.push(arg);
+            out.extend(args.next());
+            continue;
+        }
+        match arg.strip_prefix('-') {
+            Some(flags) if flags.len() > 1 && !flags.starts_with('-') => {
+                for (i, c) in flags.char_indices() {
+                    let option = format!("-{}", c);
+                    let value = takes_value(&option);
+                    out.push(option);
+                    if value {
+                        match &flags[i + c.len_utf8()..] {
+                            "" => out.extend(args.next()),
+                            rest => out.push(rest.to_string()),
+                        }
+                        break;
+                    }
+                }
+            }
+            _ => out.push(arg),
+        }
+    }
+    out
+}
+
+struct Options {
+    patterns: Vec<String>,
+    /// compile options
+    flags: u32,
+    utf: bool,
+    /// empty for stdin
+    paths: Vec<PathBuf>,
+    walk: Walk,
+    text: bool,
+    threads: Option<usize>,
+    dest: String,
+    /// `None` for grep output on stdout
+    format: Option<Format>,
+    stats: bool,
+    /// `None` for more than one file
+    with_filename: Option<bool>,
+    line_number: bool,
+    byte_offset: bool,
+    only_matching: bool,
+    count: bool,
+    files_with_matches: bool,
+    invert: bool,
+    /// lines before and after, -C for the unset
+    before: Option<usize>,
+    after: Option<usize>,
+    context: usize,
+    color: bool,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self> {
+        let mut options = Options {
+            patterns: Vec::new(),
+            flags: 0,
+            utf: false,
+            paths: Vec::new(),
+            walk: Walk::new(),
+            text: false,
+            threads: None,
+            dest: "-".to_string(),
+            format: None,
+            stats: false,
+            with_filename: None,
+            line_number: false,
+            byte_offset: false,
+            only_matching: false,
+            count: false,
+            files_with_matches: false,
+            invert: false,
+            before: None,
+            after: None,
+            context: 0,
+            color: false,
+        };
+        // the first positional argument is the pattern without -e or -f
+        let mut explicit = false;
+        let mut positional = Vec::new();
+        let mut args = expand(args).into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "-e" | "--regexp" => {
+                    options.patterns.push(value()?);
+                    explicit = true;
+                }
+                "-f" | "--file" => {
+                    let path = value()?;
+                    let file = fs::read_to_string(&path)
+                        .with_context(|| format!("pattern file {:?}", path))?;
+                    let lines = file.lines().filter(|line| !line.is_empty());
+                    options.patterns.extend(lines.map(str::to_string));
+                    explicit = true;
+                }
+                "-i" => options.flags |= PCRE2_CASELESS,
+                "-m" => options.flags |= PCRE2_MULTILINE,
+                "-s" => options.flags |= PCRE2_DOTALL,
+                "-x" => options.flags |= PCRE2_EXTENDED,
+                "-u" => {
+                    options.flags |= PCRE2_UTF | PCRE2_UCP;
+                    options.utf = true;
+                }
+                "--include" => options.walk = options.walk.include(Glob::new(&value()?)?),
+                "--exclude" => options.walk = options.walk.exclude(Glob::new(&value()?)?),
+                "--no-ignore" => options.walk = options.walk.ignore_files(false),
+                "-a" | "--text" => options.text = true,
+                "-j" | "--threads" => options.threads = Some(value()?.parse::<usize>()?.max(1)),
+                "-d" | "--dest" => options.dest = value()?,
+                "--format" => options.format = Some(value()?.parse()?),
+                "--stats" => options.stats = true,
+                "-H" | "--with-filename" => options.with_filename = Some(true),
+                "-h" | "--no-filename" => options.with_filename = Some(false),
+                "-n" | "--line-number" => options.line_number = true,
+                "-b" | "--byte-offset" => options.byte_offset = true,
+                "-o" | "--only-matching" => options.only_matching = true,
+                "-c" | "--count" => options.count = true,
+                "-l" | "--files-with-matches" => options.files_with_matches = true,
+                "-v" | "--invert-match" => options.invert = true,
+                "-A" | "--after-context" => options.after = Some(value()?.parse()?),
+                "-B" | "--before-context" => options.before = Some(value()?.parse()?),
+                "-C" | "--context" => options.context = value()?.parse()?,
+                "--color" | "--colour" => options.color = io::stdout().is_terminal(),
+                _ if arg.starts_with("--color=") || arg.starts_with("--colour=") => {
+                    options.color = match arg.split_once('=').map_or("", |(_, when)| when) {
+                        "always" => true,
+                        "never" => false,
+                        "auto" => io::stdout().is_terminal(),
+                        when => bail!("unknown --color: {}", when),
+                    }
+                }
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                "--" => positional.extend(args.by_ref()),
+                "-" => positional.push(arg),
+                _ if arg.starts_with('-') => bail!("unknown option: {}", arg),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        if !explicit {
+            let pattern = positional.next().ok_or_else(|| anyhow!("no pattern"))?;
+            options.patterns.push(pattern);
+        }
+        if options.patterns.is_empty() {
+            bail!("no pattern");
+        }
+        options.paths = positional.map(PathBuf::from).collect();
+        Ok(options)
+    }
+}
+
+/// the sender of a destination URL, records encoded in `format`
+fn destination(dest: &str, format: Format) -> Result<Box<dyn Sender + Send + Sync>> {
+    if dest == "-" {
+        return Ok(Box::new(Encoded::new(StdoutSink::new(), format)));
+    }
+    let Some((scheme, addr)) = dest.split_once("://") else {
+        bail!("not a URL: {}", dest);
+    };
+    let transport: Box<dyn Sender + Send + Sync> = match scheme {
+        "file" => return Ok(Box::new(Encoded::new(FileSink::new(addr)?, format))),
+        "udp" => Box::new(Udp::new(addr)?),
+        "tcp" => Box::new(Tcp::new(addr)?),
+        #[cfg(unix)]
+        "unix" => Box::new(xipin_resolution::sender::UnixStream::new(addr)?),
+        #[cfg(unix)]
+        "unixgram" => Box::new(xipin_resolution::sender::UnixDatagram::new(addr)?),
+        _ => bail!("unknown scheme: {}", scheme),
+    };
+    // a slow socket doesn't hold up matching
+    let transport = Encoded::new(transport, format);
+    Ok(Box::new(Pipeline::new(transport, 1024, Overflow::Block)?))
+}
+
+/// where the results go
+enum Output {
+    /// to stdout, in the order of the files
+    Lines(Grep),
+    Records(Box<dyn Sender + Send + Sync>),
+}
+
+/// true if stdout was closed, e.g. by `head`
+fn broken_pipe(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// send every match of `patterns` in `subject`, returns how many there were
+fn search<M: Matcher, S: Sender>(
+    patterns: &[M],
+    options: &Options,
+    source: &str,
+    subject: &[u8],
+    sender: &S,
+) -> Result<u64> {
+    let mut count = 0;
+    for (id, re) in patterns.iter().enumerate() {
+        // raw is the match only
+        if options.format.is_some_and(|format| format != Format::Raw) {
+            for captures in re.captures_iter(subject) {
+                let captures = captures?;
+                let m = captures.get(0).expect("captures have group 0");
+                let record = Record::new(id, source, m).with_captures(&captures);
+                sender.send_record(&record)?;
+                count += 1;
+            }
+        } else {
+            for m in re.find_iter(subject) {
+                sender.send_record(&Record::new(id, source, m?))?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// search the file at `path`, returns the number of matches, or matching
+/// lines, and the lines to print
+fn search_file<M: Matcher>(
+    matchers: &[M],
+    options: &Options,
+    output: &Output,
+    path: &Path,
+) -> Result<(u64, Vec<u8>)> {
+    let subject = Subject::open(path)?;
+    if !options.text && is_binary(&subject) {
+        return Ok((0, Vec::new()));
+    }
+    // matching skips the UTF-8 check
+    if options.utf {
+        std::str::from_utf8(&subject).context("not valid UTF-8")?;
+    }
+    let source = path.to_string_lossy();
+    match output {
+        Output::Lines(grep) => {
+            let mut lines = Vec::new();
+            let count = grep.search(matchers, &source, &subject, &mut lines)?;
+            Ok((count, lines))
+        }
+        Output::Records(sender) => {
+            let count = search(matchers, options, &source, &subject, sender)?;
+            Ok((count, Vec::new()))
+        }
+    }
+}
+
+/// exit code of the whole run
+fn run(options: &Options) -> Result<i32> {
+    let patterns = options
+        .patterns
+        .iter()
+        .map(|p| {
+            PCRE2Builder::new()
+                .options(options.flags)
+                .build(p)
+                .with_context(|| format!("pattern {:?}", p))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = match (options.dest.as_str(), options.format) {
+        ("-", None) => {
+            let with_filename = options
+                .with_filename
+                .unwrap_or(options.paths.len() > 1 || options.paths.iter().any(|p| p.is_dir()));
+            let grep = Grep::new()
+                .with_filename(with_filename)
+                .line_number(options.line_number)
+                .byte_offset(options.byte_offset)
+                .only_matching(options.only_matching)
+                .count(options.count)
+                .files_with_matches(options.files_with_matches)
+                .invert(options.invert)
+                .context(
+                    options.before.unwrap_or(options.context),
+                    options.after.unwrap_or(options.context),
+                )
+                .color(options.color);
+            Output::Lines(grep)
+        }
+        (dest, format) => Output::Records(destination(dest, format.unwrap_or_default())?),
+    };
+
+    let paths = match options.paths.is_empty() {
+        true => vec![PathBuf::from("-")],
+        false => options.paths.clone(),
+    };
+    let files = Mutex::new(options.walk.files(paths).enumerate());
+    let threads = options
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    // every thread matches with its own match data, the compiled patterns are
+    // shared
+    let matchers = (0..threads)
+        .map(|_| patterns.iter().map(PCRE2::share).collect())
+        .collect::<Result<Vec<Vec<_>>>>()?;
+    let stop = AtomicBool::new(false);
+
+    let (mut matched, mut failed) = (false, false);
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        for matchers in matchers {
+            let (tx, files, stop, output) = (tx.clone(), &files, &stop, &output);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let Some((i, file)) = files.lock().unwrap().next() else {
+                        return;
+                    };
+                    let result = file.and_then(|path| {
+                        search_file(&matchers, options, output, &path)
+                            .with_context(|| path.display().to_string())
+                    });
+                    if tx.send((i, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // printed in the order of the walk
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&next) {
+                next += 1;
+                let result = result.and_then(|(count, lines)| {
+                    matched |= count > 0;
+                    if !lines.is_empty() {
+                        io::stdout().lock().write_all(&lines)?;
+                    }
+                    Ok(())
+                });
+                match result {
+                    Ok(()) => {}
+                    Err(e) if broken_pipe(&e) => {
+                        stop.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("{:#}", e);
+                        failed = true;
+                    }
+                }
+            }
+        }
+    });
+    if stop.load(Ordering::Relaxed) {
+        return Ok(if matched { 0 } else { 1 });
+    }
+
+    let flushed = match &output {
+        Output::Lines(_) => io::stdout().flush().map_err(Into::into),
+        Output::Records(sender) => sender.flush(),
+    };
+    match flushed {
+        Err(e) if !broken_pipe(&e) => {
+            eprintln!("flush error: {:#}", e);
+            failed = true;
+        }
+        _ => {}
+    }
+    if let (true, Output::Records(sender)) = (options.stats, &output) {
+        eprintln!("{}", sender.stats());
+    }
+    Ok(match (failed, matched) {
+        (true, _) => 2,
+        (false, true) => 0,
+        (false, false) => 1,
+    })
+}
 
 fn main() {
-    // just hard code to run
-    // TODO: cli parser
-    let udp = Udp::new("127.0.0.1:7878").unwrap();
-
-    let subject = r"a;jhgoqoghqoj0329 u0tyu10hg0h9Y0Y9827342482y(Y0y(G)_)lajf;lqjfgqhgpqjopjqa=)*(^!@#$%^&*())9999999";
-    // Use Positive Lookbehind (?<=) and Positive Lookahead (?=)
-    let pattern = r"(?<=\d{4})[^\d\s]{3,11}(?=\S)";
-
-    let grep = PCRE2::new(pattern).unwrap();
-
-    for m in grep.find_iter(subject.as_bytes()) {
-        match m {
-            Ok(s) => match udp.send(s.as_bytes()) {
-                Ok(len) => print!("send {:?} bytes: {:?}", len, s.to_string()),
-                Err(e) => print!("send error: {:?}", e),
-            },
-            Err(e) => println!("match error: {:?}", e),
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    match run(&options) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            process::exit(2);
         }
     }
 }
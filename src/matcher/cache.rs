@@ -0,0 +1,243 @@
+//! A bounded LRU cache of compiled patterns.
+//!
+//! Compiling is the expensive step of [`PCRE2Builder::build`], so callers that
+//! see the same pattern again and again can ask the cache instead. Entries are
+//! keyed by `(pattern, options, extra options)` and evicted least recently used
+//! first, when either the entry count or the total compiled size reported by
+//! `PCRE2_INFO_SIZE` goes over its limit.
+//!
+//! What the cache hands out is a [`PCRE2::share`] of the cached matcher: the
+//! compiled pattern is shared, the match data is its own, so it can go to
+//! another thread.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::{PCRE2Builder, PCRE2};
+
+/// `(options, extra options)`, the patterns of each are looked up by `&str`
+/// so a hit doesn't allocate a key
+type Options = (u32, u32);
+
+struct Entry {
+    re: PCRE2,
+    /// compiled size in bytes
+    size: usize,
+    /// last access tick, the smallest one is evicted first
+    used: u64,
+}
+
+/// Counters of a [`PatternCache`], cheap to copy out and export.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// number of cached patterns
+    pub entries: usize,
+    /// total compiled size of the cached patterns in bytes
+    pub bytes: usize,
+}
+
+pub struct PatternCache {
+    entries: HashMap<Options, HashMap<Arc<str>, Entry>>,
+    /// the entries by last access tick, the first one is evicted first
+    lru: BTreeMap<u64, (Arc<str>, Options)>,
+    len: usize,
+    /// max number of cached patterns
+    capacity: usize,
+    /// max total compiled size in bytes
+    max_bytes: usize,
+    bytes: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl PatternCache {
+    /// cache at most `capacity` patterns, without a size limit
+    pub fn new(capacity: usize) -> Self {
+        Self::with_limits(capacity, usize::MAX)
+    }
+
+    /// cache at most `capacity` patterns and `max_bytes` of compiled code
+    pub fn with_limits(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            len: 0,
+            capacity,
+            max_bytes,
+            bytes: 0,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// same as [`PCRE2::new`], but shared
+    pub fn get(&mut self, pattern: &str) -> Result<PCRE2> {
+        self.get_with(pattern, 0, 0)
+    }
+
+    /// return the cached pattern, or compile and cache it.
+    /// A pattern larger than the size limit is returned but not cached.
+    pub fn get_with(&mut self, pattern: &str, options: u32, extra_options: u32) -> Result<PCRE2> {
+        let key = (options, extra_options);
+        self.tick += 1;
+        let entry = self
+            .entries
+            .get_mut(&key)
+            .and_then(|patterns| patterns.get_mut(pattern));
+        if let Some(entry) = entry {
+            let lru = self.lru.remove(&entry.used).expect("cached entry in lru");
+            entry.used = self.tick;
+            self.lru.insert(self.tick, lru);
+            self.hits += 1;
+            return entry.re.share();
+        }
+        self.misses += 1;
+
+        let re = PCRE2Builder::new()
+            .options(options)
+            .extra_options(extra_options)
+            .build(pattern)?;
+        let size = re.pattern().size()?;
+        if self.capacity == 0 || size > self.max_bytes {
+            return Ok(re);
+        }
+        while self.len >= self.capacity || self.bytes + size > self.max_bytes {
+            self.evict();
+        }
+        let shared = re.share()?;
+        let pattern: Arc<str> = pattern.into();
+        self.bytes += size;
+        self.len += 1;
+        self.lru.insert(self.tick, (Arc::clone(&pattern), key));
+        self.entries.entry(key).or_default().insert(
+            pattern,
+            Entry {
+                re,
+                size,
+                used: self.tick,
+            },
+        );
+        Ok(shared)
+    }
+
+    // drop the least recently used entry, handles given out stay valid
+    fn evict(&mut self) {
+        let Some((_, (pattern, key))) = self.lru.pop_first() else {
+            return;
+        };
+        let Some(patterns) = self.entries.get_mut(&key) else {
+            return;
+        };
+        if let Some(entry) = patterns.remove(&pattern) {
+            self.bytes -= entry.size;
+            self.len -= 1;
+            self.evictions += 1;
+        }
+        if patterns.is_empty() {
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// drop all entries, the counters are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.len = 0;
+        self.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.len,
+            bytes: self.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::Matcher;
+    use pcre2_sys::PCRE2_CASELESS;
+
+    #[test]
+    fn test_cache_hit_miss() {
+        let mut cache = PatternCache::new(4);
+        let a = cache.get(r"\d{4}").unwrap();
+        let b = cache.get(r"\d{4}").unwrap();
+        assert!(std::ptr::eq(a.pattern(), b.pattern()));
+        let c = cache.get_with(r"\d{4}", PCRE2_CASELESS, 0).unwrap();
+        assert!(!std::ptr::eq(a.pattern(), c.pattern()));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        assert!(cache.get("*").is_err());
+
+        // handles and the cache itself can move to another thread
+        let mut cache = std::thread::spawn(move || {
+            assert!(a.is_match(b"2023").unwrap());
+            cache
+        })
+        .join()
+        .unwrap();
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evict_by_count() {
+        let mut cache = PatternCache::new(2);
+        cache.get("a").unwrap();
+        cache.get("b").unwrap();
+        // touch `a`, so `b` is the least recently used
+        cache.get("a").unwrap();
+        cache.get("c").unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 1);
+        cache.get("a").unwrap();
+        assert_eq!(cache.stats().hits, 2);
+        cache.get("b").unwrap();
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn test_cache_evict_by_size() {
+        let size = PCRE2Builder::new()
+            .build("a")
+            .unwrap()
+            .pattern()
+            .size()
+            .unwrap();
+        let mut cache = PatternCache::with_limits(16, size * 2);
+        cache.get("a").unwrap();
+        cache.get("b").unwrap();
+        cache.get("c").unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert!(stats.bytes <= size * 2);
+        // too large to be cached at all
+        let long = "x".repeat(size * 2);
+        let re = cache.get(&long).unwrap();
+        assert!(re.is_match(long.as_bytes()).unwrap());
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+//! Literal prefilter, run before calling into `PCRE2`.
+//!
+//! When the compiled pattern reports a fixed first code unit
+//! (`PCRE2_INFO_FIRSTCODEUNIT`) or a required one (`PCRE2_INFO_LASTCODEUNIT`),
+//! any match must contain that byte at or after the start offset. A `memchr`
+//! scan for it is much cheaper than a `pcre2_match` call, so subjects, or
+//! whole chunks, without it are skipped.
+//!
+//! PCRE2 reports the same code unit for caseless patterns and keeps the
+//! caseless flag to itself, so an ASCII letter is searched in both cases unless
+//! the pattern info shows it can't be caseless. With the default character
+//! tables no other byte has a case.
+
+use anyhow::Result;
+use libc::c_void;
+use pcre2_sys::*;
+
+use super::Pattern;
+
+/// a byte any match must contain, with its other case if it has one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Literal {
+    byte: u8,
+    other: Option<u8>,
+}
+
+impl Literal {
+    pub fn new(byte: u8, caseless: bool) -> Self {
+        let other = match byte {
+            b'a'..=b'z' | b'A'..=b'Z' if caseless => Some(byte ^ 0x20),
+            _ => None,
+        };
+        Literal { byte, other }
+    }
+
+    /// the offset of the first occurrence in `haystack`
+    pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let first = memchr(self.byte, haystack);
+        match self.other {
+            // only scan up to the first hit of the other case
+            Some(other) => match memchr(other, &haystack[..first.unwrap_or(haystack.len())]) {
+                None => first,
+                found => found,
+            },
+            None => first,
+        }
+    }
+}
+
+/// `libc::memchr`, vectorized by the C library
+fn memchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+    if haystack.is_empty() {
+        return None;
+    }
+    let p = unsafe {
+        libc::memchr(
+            haystack.as_ptr() as *const c_void,
+            byte as libc::c_int,
+            haystack.len(),
+        )
+    };
+    if p.is_null() {
+        None
+    } else {
+        Some(p as usize - haystack.as_ptr() as usize)
+    }
+}
+
+/// The literals extracted from a compiled pattern.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Prefilter {
+    /// every match starts with it
+    first: Option<Literal>,
+    /// every match contains it, other than at its start
+    required: Option<Literal>,
+}
+
+/// whether the literals of a pattern compiled with `options`, as
+/// `PCRE2_INFO_ALLOPTIONS` reports them, may be caseless: with `CASELESS`, or
+/// unless `LITERAL` rules out an inline `(?i)`, which the info doesn't show,
+/// nor does `[Tt]` folded into a caseless `t`
+fn may_be_caseless(options: u32) -> bool {
+    options & PCRE2_CASELESS != 0 || options & PCRE2_LITERAL == 0
+}
+
+impl Prefilter {
+    pub fn new(pattern: &Pattern) -> Result<Self> {
+        // type 1 means a fixed code unit, 2 means start of line
+        let first = match pattern.info::<u32>(PCRE2_INFO_FIRSTCODETYPE)? {
+            1 => Some(pattern.info::<u32>(PCRE2_INFO_FIRSTCODEUNIT)?),
+            _ => None,
+        };
+        let required = match pattern.info::<u32>(PCRE2_INFO_LASTCODETYPE)? {
+            1 => Some(pattern.info::<u32>(PCRE2_INFO_LASTCODEUNIT)?),
+            _ => None,
+        };
+        let caseless = may_be_caseless(pattern.info(PCRE2_INFO_ALLOPTIONS)?);
+        let literal = |unit: u32| Literal::new(unit as u8, caseless);
+        Ok(Prefilter {
+            first: first.map(literal),
+            required: required.map(literal),
+        })
+    }
+
+    /// whether there is anything to scan for
+    pub fn is_empty(&self) -> bool {
+        self.first.is_none() && self.required.is_none()
+    }
+
+    pub fn first(&self) -> Option<Literal> {
+        self.first
+    }
+
+    pub fn required(&self) -> Option<Literal> {
+        self.required
+    }
+
+    /// false if no match can start at or after `start`,
+    /// true doesn't mean there is a match
+    pub fn may_match(&self, subject: &[u8], start: usize) -> bool {
+        let haystack = match subject.get(start..) {
+            Some(haystack) => haystack,
+            None => return false,
+        };
+        let from = match self.first {
+            Some(first) => match first.find(haystack) {
+                Some(pos) => pos,
+                None => return false,
+            },
+            None => 0,
+        };
+        match self.required {
+            Some(required) => required.find(&haystack[from..]).is_some(),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{Matcher, PCRE2Builder, PCRE2};
+
+    #[test]
+    fn test_literal_find() {
+        let lit = Literal::new(b'e', true);
+        assert_eq!(lit.find(b"abcdEfe"), Some(4));
+        assert_eq!(lit.find(b"abcd"), None);
+        let lit = Literal::new(b'e', false);
+        assert_eq!(lit.find(b"abcdEfe"), Some(6));
+        let lit = Literal::new(b'=', true);
+        assert_eq!(lit.find(b"a=b"), Some(1));
+        assert_eq!(lit.find(b""), None);
+    }
+
+    #[test]
+    fn test_prefilter_extract() {
+        let re = PCRE2Builder::new().build(r"error: \d+").unwrap();
+        let filter = re.prefilter().unwrap();
+        assert_eq!(filter.first(), Some(Literal::new(b'e', true)));
+        assert_eq!(filter.required(), Some(Literal::new(b' ', false)));
+        assert!(!filter.may_match(b"info: 42", 0));
+        assert!(!filter.may_match(b"error: 42", 1));
+        assert!(filter.may_match(b"error: 42", 0));
+
+        let re = PCRE2Builder::new().build(r"\d+|\s+").unwrap();
+        assert!(re.prefilter().is_none());
+    }
+
+    #[test]
+    fn test_may_be_caseless() {
+        let first = |re: PCRE2| re.prefilter().unwrap().first().unwrap();
+        let re = PCRE2Builder::new().add_option(PCRE2_LITERAL);
+        assert_eq!(first(re.build("e(?i)").unwrap()), Literal::new(b'e', false));
+        let re = PCRE2Builder::new().add_option(PCRE2_LITERAL | PCRE2_CASELESS);
+        assert_eq!(first(re.build("error").unwrap()), Literal::new(b'e', true));
+        for source in ["[Ee]rror", "(?i)error", r"\x45(?i)rror", "(*UTF)error"] {
+            let re = PCRE2Builder::new().build(source).unwrap();
+            assert!(first(re).find(b"ERROR").is_some(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_prefilter_caseless() {
+        let re = PCRE2Builder::new().build("(?i)warn").unwrap();
+        assert!(re.is_match(b"a WARNING").unwrap());
+        let re = PCRE2Builder::new()
+            .add_option(PCRE2_CASELESS)
+            .build("warn")
+            .unwrap();
+        assert!(re.is_match(b"a WARNING").unwrap());
+        assert!(Matcher::find_at(&re, b"no match", 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_prefilter_match_options() {
+        // a partial match has neither the `:` nor the ` `
+        let re = PCRE2Builder::new().build(r"error: \d+").unwrap();
+        let e = re
+            .find_at_with_options(b"an err", 0, PCRE2_PARTIAL_HARD)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("find error: {}", PCRE2_ERROR_PARTIAL)
+        );
+        let e = re
+            .find_at_with_options(b"an err", 0, PCRE2_NO_UTF_CHECK)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "No match items");
+    }
+
+    #[test]
+    fn test_prefilter_disabled() {
+        let re = PCRE2Builder::new()
+            .prefilter(false)
+            .build("needle")
+            .unwrap();
+        assert!(re.prefilter().is_none());
+        assert!(re.is_match(b"hay needle hay").unwrap());
+    }
+}
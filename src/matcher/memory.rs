@@ -0,0 +1,222 @@
+//! Route `PCRE2` allocations through a Rust allocator.
+//!
+//! `pcre2_general_context_create` takes a private `malloc`/`free` pair and an
+//! opaque `memory_data` pointer that is handed back on every call. We point it
+//! at a [`Memory`] that forwards to a [`GlobalAlloc`] and keeps count of the
+//! bytes in use, so the memory of one tenant can be accounted and capped.
+//!
+//! PCRE2 copies the memory functions into every compile context, compiled
+//! pattern, match context and match data block created from the general
+//! context, and calls them again when those are freed. So everything created
+//! from a [`GeneralContext`] keeps a clone of it alive.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use libc::c_void;
+use pcre2_sys::*;
+
+/// room in front of every block to remember its size, `free` doesn't tell us.
+/// 16 keeps the user block aligned like `malloc` does.
+const HEADER: usize = 16;
+
+struct Memory {
+    alloc: Box<dyn GlobalAlloc + Send + Sync>,
+    /// bytes requested by PCRE2 and not freed yet
+    in_use: AtomicUsize,
+    /// high water mark of `in_use`
+    peak: AtomicUsize,
+    /// allocations going over this fail, PCRE2 reports it as `PCRE2_ERROR_NOMEMORY`
+    limit: AtomicUsize,
+}
+
+impl Memory {
+    fn layout(size: usize) -> Option<Layout> {
+        let size = size.checked_add(HEADER)?;
+        Layout::from_size_align(size, HEADER).ok()
+    }
+
+    unsafe fn malloc(&self, size: usize) -> *mut u8 {
+        let layout = match Memory::layout(size) {
+            Some(layout) => layout,
+            None => return ptr::null_mut(),
+        };
+        let limit = self.limit.load(Ordering::Relaxed);
+        let in_use = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        if in_use > limit {
+            self.in_use.fetch_sub(size, Ordering::Relaxed);
+            return ptr::null_mut();
+        }
+        let base = self.alloc.alloc(layout);
+        if base.is_null() {
+            self.in_use.fetch_sub(size, Ordering::Relaxed);
+            return base;
+        }
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        (base as *mut usize).write(size);
+        base.add(HEADER)
+    }
+
+    unsafe fn free(&self, block: *mut u8) {
+        let base = block.sub(HEADER);
+        let size = (base as *const usize).read();
+        // SAFETY: the same layout was valid when allocating
+        let layout = Layout::from_size_align_unchecked(size + HEADER, HEADER);
+        self.alloc.dealloc(base, layout);
+        self.in_use.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe extern "C" fn private_malloc(size: usize, data: *mut c_void) -> *mut c_void {
+    let memory = &*(data as *const Memory);
+    memory.malloc(size) as *mut c_void
+}
+
+unsafe extern "C" fn private_free(block: *mut c_void, data: *mut c_void) {
+    if block.is_null() {
+        return;
+    }
+    let memory = &*(data as *const Memory);
+    memory.free(block as *mut u8)
+}
+
+struct Inner {
+    ctx: *mut pcre2_general_context_8,
+    // boxed, PCRE2 holds on to its address
+    memory: Box<Memory>,
+}
+
+// SAFETY: PCRE2 only reads the general context when creating other contexts,
+// and the memory functions behind it only touch atomics and a `Sync` allocator.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // freed by our own `private_free`, `memory` is still alive here
+        unsafe { pcre2_general_context_free_8(self.ctx) }
+    }
+}
+
+/// A `pcre2_general_context` whose allocations go through a Rust allocator.
+///
+/// Cloning is cheap and shares the same accounting.
+#[derive(Clone)]
+pub struct GeneralContext(Arc<Inner>);
+
+impl GeneralContext {
+    /// use the system allocator, without a limit
+    pub fn new() -> Self {
+        Self::with_allocator(System)
+    }
+
+    // panic when allocate failed
+    pub fn with_allocator<A: GlobalAlloc + Send + Sync + 'static>(alloc: A) -> Self {
+        let memory = Box::new(Memory {
+            alloc: Box::new(alloc),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+        });
+        let data = &*memory as *const Memory as *mut c_void;
+        let ctx = unsafe {
+            pcre2_general_context_create_8(Some(private_malloc), Some(private_free), data)
+        };
+        assert!(!ctx.is_null(), "context allocate fail");
+        Self(Arc::new(Inner { ctx, memory }))
+    }
+
+    /// cap the bytes in use, it doesn't free what's already allocated
+    pub fn set_limit(&self, bytes: usize) {
+        self.0.memory.limit.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> usize {
+        self.0.memory.limit.load(Ordering::Relaxed)
+    }
+
+    /// bytes allocated by PCRE2 and not freed yet,
+    /// the general context itself included
+    pub fn in_use(&self) -> usize {
+        self.0.memory.in_use.load(Ordering::Relaxed)
+    }
+
+    /// the most bytes ever in use at the same time
+    pub fn peak(&self) -> usize {
+        self.0.memory.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut pcre2_general_context_8 {
+        self.0.ctx
+    }
+}
+
+impl fmt::Debug for GeneralContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneralContext")
+            .field("in_use", &self.in_use())
+            .field("peak", &self.peak())
+            .field("limit", &self.limit())
+            .finish()
+    }
+}
+
+impl Default for GeneralContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// map a null pointer from a `*_create` call on a capped context to an error
+pub(crate) fn check_alloc<T>(ptr: *mut T, what: &str) -> Result<*mut T> {
+    if ptr.is_null() {
+        bail!("failed to allocate {}", what);
+    }
+    Ok(ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{CompileContext, MatchData, Matcher, PCRE2Builder, Pattern};
+
+    #[test]
+    fn test_general_context_accounting() {
+        let gctx = GeneralContext::new();
+        let base = gctx.in_use();
+        assert!(base > 0);
+        {
+            let ctx = CompileContext::new_in(&gctx).unwrap();
+            let pattern = Pattern::new_with(r"\d{4}[a-z]+", 0, ctx).unwrap();
+            let size = pattern.size().unwrap();
+            assert!(gctx.in_use() >= base + size);
+            let _data = MatchData::new_in(&pattern, &gctx).unwrap();
+        }
+        assert_eq!(gctx.in_use(), base);
+        assert!(gctx.peak() > base);
+    }
+
+    #[test]
+    fn test_general_context_limit() {
+        let gctx = GeneralContext::new();
+        gctx.set_limit(gctx.in_use() + 64);
+        let re = PCRE2Builder::new()
+            .general_context(gctx.clone())
+            .build(&"x".repeat(256));
+        assert!(re.is_err());
+
+        gctx.set_limit(usize::MAX);
+        let re = PCRE2Builder::new()
+            .general_context(gctx.clone())
+            .build(r"\d{4}")
+            .unwrap();
+        assert!(re.is_match(b"abc2023").unwrap());
+        drop(gctx);
+        // the pattern keeps the context alive
+        assert!(re.is_match(b"2024").unwrap());
+    }
+}
@@ -1,6 +1,14 @@
 #![allow(dead_code)]
 
+use anyhow::{anyhow, Result};
+
+mod cache;
+mod literal;
+mod memory;
 mod pcre2;
+pub use cache::*;
+pub use literal::*;
+pub use memory::*;
 pub use pcre2::*;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -37,11 +45,269 @@ impl<'s> Match<'s> {
         self.subject
     }
 
-    pub fn to_string(&self) -> &str {
+    pub fn to_string(self) -> &'s str {
         std::str::from_utf8(self.subject).unwrap()
     }
 }
 
+/// The captures of one match, group 0 is the whole match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Captures<'s> {
+    subject: &'s [u8],
+    /// byte offsets of every group, `None` if it didn't participate
+    locations: Vec<Option<(usize, usize)>>,
+}
+
+impl<'s> Captures<'s> {
+    pub fn new(subject: &'s [u8], locations: Vec<Option<(usize, usize)>>) -> Captures<'s> {
+        Captures { subject, locations }
+    }
+
+    /// Returns the match of group `i`, if it participated in the match.
+    pub fn get(&self, i: usize) -> Option<Match<'s>> {
+        let (start, end) = (*self.locations.get(i)?)?;
+        Some(Match::new(&self.subject[start..end], start, end))
+    }
+
+    /// Returns the number of groups, group 0 included.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Option<Match<'s>>> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+/// A matching engine, the pipeline is written against this trait
+/// so `PCRE2`, a DFA or literal engine, or a test double can be plugged in.
+///
+/// An `Err` is a failure of the engine, e.g. a hit match limit,
+/// while no match is `Ok(None)`.
 pub trait Matcher {
-    fn find(&self);
+    /// Returns the leftmost match starting the search at byte offset `start`.
+    fn find_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Match<'s>>>;
+
+    /// Same as [`Matcher::find_at`], with the capture groups.
+    fn captures_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Captures<'s>>>;
+
+    /// Returns all the successive non-overlapping matches.
+    fn find_iter<'m, 's>(&'m self, subject: &'s [u8]) -> Matches<'m, 's, Self>
+    where
+        Self: Sized,
+    {
+        Matches::new(self, subject)
+    }
+
+    /// Same as [`Matcher::find_iter`], with the capture groups of every match.
+    fn captures_iter<'m, 's>(&'m self, subject: &'s [u8]) -> CaptureMatches<'m, 's, Self>
+    where
+        Self: Sized,
+    {
+        CaptureMatches::new(self, subject)
+    }
+
+    fn is_match(&self, subject: &[u8]) -> Result<bool> {
+        Ok(self.find_at(subject, 0)?.is_some())
+    }
+
+    /// Returns the end offset of the match that ends first,
+    /// engines may stop early instead of finding the leftmost-first match.
+    fn shortest_match(&self, subject: &[u8]) -> Result<Option<usize>> {
+        Ok(self.find_at(subject, 0)?.map(|m| m.end()))
+    }
+}
+
+/// Where the next search of an iterator starts.
+#[derive(Default)]
+struct Cursor {
+    last_end: usize,
+    last_match: Option<usize>,
+}
+
+impl Cursor {
+    /// move past the match at `start..end`, false if it's to be skipped
+    fn advance(&mut self, start: usize, end: usize) -> bool {
+        if start == end {
+            // This is an empty match. To ensure we make progress, start
+            // the next search at the smallest possible starting position
+            // of the next match following this one.
+            self.last_end = end + 1;
+            // Don't accept empty matches immediately following a match.
+            // Just move on to the next match.
+            if Some(end) == self.last_match {
+                return false;
+            }
+        } else {
+            self.last_end = end;
+        }
+        self.last_match = Some(end);
+        true
+    }
+}
+
+/// The iterator of [`Matcher::find_iter`], an error ends the iteration.
+pub struct Matches<'m, 's, M: ?Sized> {
+    re: &'m M,
+    subject: &'s [u8],
+    cursor: Cursor,
+}
+
+impl<'m, 's, M: Matcher + ?Sized> Matches<'m, 's, M> {
+    pub fn new(re: &'m M, subject: &'s [u8]) -> Self {
+        Matches {
+            re,
+            subject,
+            cursor: Cursor::default(),
+        }
+    }
+}
+
+impl<'m, 's, M: Matcher + ?Sized> Iterator for Matches<'m, 's, M> {
+    type Item = Result<Match<'s>>;
+
+    fn next(&mut self) -> Option<Result<Match<'s>>> {
+        loop {
+            if self.cursor.last_end > self.subject.len() {
+                return None;
+            }
+            let m = match self.re.find_at(self.subject, self.cursor.last_end) {
+                Err(err) => {
+                    self.cursor.last_end = self.subject.len() + 1;
+                    return Some(Err(err));
+                }
+                Ok(None) => return None,
+                Ok(Some(m)) => m,
+            };
+            if self.cursor.advance(m.start(), m.end()) {
+                return Some(Ok(m));
+            }
+        }
+    }
+}
+
+/// The iterator of [`Matcher::captures_iter`], an error ends the iteration.
+///
+/// It moves on from the end of group 0, the match reported, so the captures
+/// are those of the match itself even where it doesn't start at the offset
+/// matching began from, e.g. after a `\K`.
+pub struct CaptureMatches<'m, 's, M: ?Sized> {
+    re: &'m M,
+    subject: &'s [u8],
+    cursor: Cursor,
+}
+
+impl<'m, 's, M: Matcher + ?Sized> CaptureMatches<'m, 's, M> {
+    pub fn new(re: &'m M, subject: &'s [u8]) -> Self {
+        CaptureMatches {
+            re,
+            subject,
+            cursor: Cursor::default(),
+        }
+    }
+}
+
+impl<'m, 's, M: Matcher + ?Sized> Iterator for CaptureMatches<'m, 's, M> {
+    type Item = Result<Captures<'s>>;
+
+    fn next(&mut self) -> Option<Result<Captures<'s>>> {
+        loop {
+            if self.cursor.last_end > self.subject.len() {
+                return None;
+            }
+            let captures = match self.re.captures_at(self.subject, self.cursor.last_end) {
+                Err(err) => {
+                    self.cursor.last_end = self.subject.len() + 1;
+                    return Some(Err(err));
+                }
+                Ok(None) => return None,
+                Ok(Some(captures)) => captures,
+            };
+            let Some(m) = captures.get(0) else {
+                self.cursor.last_end = self.subject.len() + 1;
+                return Some(Err(anyhow!("captures without group 0")));
+            };
+            if self.cursor.advance(m.start(), m.end()) {
+                return Some(Ok(captures));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a test double finding a fixed literal
+    struct Literal(&'static [u8]);
+
+    impl Matcher for Literal {
+        fn find_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Match<'s>>> {
+            let pos = subject[start..]
+                .windows(self.0.len())
+                .position(|w| w == self.0);
+            Ok(pos.map(|pos| {
+                let (start, end) = (start + pos, start + pos + self.0.len());
+                Match::new(&subject[start..end], start, end)
+            }))
+        }
+
+        fn captures_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Captures<'s>>> {
+            let m = self.find_at(subject, start)?;
+            Ok(m.map(|m| Captures::new(subject, vec![Some((m.start(), m.end()))])))
+        }
+    }
+
+    fn count<M: Matcher>(re: &M, subject: &[u8]) -> usize {
+        re.find_iter(subject).filter(|m| m.is_ok()).count()
+    }
+
+    #[test]
+    fn test_matcher_pluggable() {
+        let subject = b"ab 2023 ab 2024 ab";
+        assert_eq!(count(&Literal(b"ab"), subject), 3);
+        assert_eq!(count(&PCRE2::new(r"\d{4}").unwrap(), subject), 2);
+        assert_eq!(Literal(b"20").shortest_match(subject).unwrap(), Some(5));
+        assert!(!Literal(b"x").is_match(subject).unwrap());
+    }
+
+    #[test]
+    fn test_find_iter_empty_matches() {
+        let re = PCRE2::new(r"\d*").unwrap();
+        let found: Vec<_> = Matcher::find_iter(&re, b"a12b")
+            .map(|m| m.unwrap().start())
+            .collect();
+        assert_eq!(found, vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn test_captures_iter() {
+        let re = PCRE2::new(r"(\d)*").unwrap();
+        let found: Vec<_> = re
+            .captures_iter(b"a12b")
+            .map(|c| {
+                let c = c.unwrap();
+                (c.get(0).unwrap().start(), c.get(1).map(|m| m.start()))
+            })
+            .collect();
+        assert_eq!(found, vec![(0, None), (1, Some(2)), (4, None)]);
+
+        // the reported start isn't where matching began
+        let re = PCRE2::new(r"(foo)\K(bar)").unwrap();
+        let found: Vec<_> = re
+            .captures_iter(b"foobar foobar")
+            .map(|c| {
+                let c = c.unwrap();
+                let group = |i| c.get(i).unwrap().start();
+                (group(0), group(1), group(2))
+            })
+            .collect();
+        assert_eq!(found, vec![(3, 0, 3), (10, 7, 10)]);
+    }
 }
@@ -18,19 +18,38 @@ use std::ops::BitAnd;
 use std::ptr;
 use std::slice;
 use std::str;
+use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 
-use super::Match;
+use super::memory::check_alloc;
+use super::{Captures, GeneralContext, Match, Matcher, Matches, Prefilter};
 
-pub struct CompileContext(*mut pcre2_compile_context_8);
+/// the general context, if any, must outlive everything created from it
+pub struct CompileContext(*mut pcre2_compile_context_8, Option<GeneralContext>);
 
 impl CompileContext {
     // panic when allocate failed
     pub fn new() -> Self {
         let ctx = unsafe { pcre2_compile_context_create_8(ptr::null_mut()) };
         assert!(!ctx.is_null(), "context allocate fail");
-        Self(ctx)
+        Self(ctx, None)
+    }
+
+    /// allocate the context and the patterns compiled with it from `gctx`
+    pub fn new_in(gctx: &GeneralContext) -> Result<Self> {
+        let ctx = unsafe { pcre2_compile_context_create_8(gctx.as_mut_ptr()) };
+        let ctx = check_alloc(ctx, "compile context")?;
+        Ok(Self(ctx, Some(gctx.clone())))
+    }
+
+    /// set the `PCRE2_EXTRA_*` options, see `pcre2_set_compile_extra_options`
+    pub fn set_extra_options(&mut self, options: u32) -> Result<()> {
+        let rc = unsafe { pcre2_set_compile_extra_options_8(self.0, options) };
+        if rc != 0 {
+            bail!("invalid compile extra option: {}", options);
+        }
+        Ok(())
     }
 
     fn as_mut_ptr(&mut self) -> *mut pcre2_compile_context_8 {
@@ -38,6 +57,12 @@ impl CompileContext {
     }
 }
 
+impl Default for CompileContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for CompileContext {
     fn drop(&mut self) {
         unsafe { pcre2_compile_context_free_8(self.0) }
@@ -83,7 +108,6 @@ impl Drop for CompileContext {
 ///     NO_UTF_CHECK = 0x40000000,        /* C   M D */
 ///     ANCHORED = 0x80000000,            /* C   M D */
 /// }
-
 pub const OPTION_MASK: u32 = !0xe35efeef;
 
 fn is_option_valid(option: u32) -> bool {
@@ -93,16 +117,24 @@ fn is_option_valid(option: u32) -> bool {
 #[derive(Debug)]
 pub struct Pattern {
     code: *mut pcre2_code_8,
+    /// keeps the allocator of `code` alive
+    gctx: Option<GeneralContext>,
 }
 
 impl Default for Pattern {
     fn default() -> Self {
         Self {
             code: ptr::null_mut(),
+            gctx: None,
         }
     }
 }
 
+// SAFETY: compiled code is only read while matching, PCRE2 allows sharing it
+// between threads as long as it isn't JIT compiled meanwhile, which we never do.
+unsafe impl Send for Pattern {}
+unsafe impl Sync for Pattern {}
+
 impl Drop for Pattern {
     fn drop(&mut self) {
         unsafe { pcre2_code_free_8(self.code) }
@@ -134,20 +166,52 @@ impl Pattern {
         if code.is_null() {
             bail!("pattern compile error: {:?} {}", error_code, error_offset)
         }
-        Ok(Self { code })
+        Ok(Self {
+            code,
+            gctx: ctx.1.clone(),
+        })
     }
 
     pub fn as_ptr(&self) -> *const pcre2_code_8 {
         self.code
     }
+
+    /// query the compiled pattern with `pcre2_pattern_info`,
+    /// `T` must match the type documented for the `what` item
+    pub(crate) fn info<T: Default>(&self, what: u32) -> Result<T> {
+        let mut value = T::default();
+        let rc = unsafe {
+            pcre2_pattern_info_8(self.code, what, &mut value as *mut T as *mut libc::c_void)
+        };
+        if rc != 0 {
+            bail!("pattern info error: {} {}", what, rc);
+        }
+        Ok(value)
+    }
+
+    /// the size of the compiled pattern in bytes, see `PCRE2_INFO_SIZE`
+    pub fn size(&self) -> Result<usize> {
+        self.info(PCRE2_INFO_SIZE)
+    }
+
+    /// the highest capture group number, see `PCRE2_INFO_CAPTURECOUNT`
+    pub fn capture_count(&self) -> Result<u32> {
+        self.info(PCRE2_INFO_CAPTURECOUNT)
+    }
 }
 
 pub struct MatchData {
     data: *mut pcre2_match_data_8,
     ovector_ptr: *const usize,
     ovector_cnt: u32,
+    /// keeps the allocator of `data` alive
+    gctx: Option<GeneralContext>,
 }
 
+// SAFETY: the block is owned, one thread at a time may match with it. It isn't
+// `Sync`, matching writes the ovector through `&self`.
+unsafe impl Send for MatchData {}
+
 impl Drop for MatchData {
     fn drop(&mut self) {
         unsafe {
@@ -161,6 +225,32 @@ impl MatchData {
         let data =
             unsafe { pcre2_match_data_create_from_pattern_8(pattern.as_ptr(), ptr::null_mut()) };
         assert!(!data.is_null(), "failed to allocate match data block");
+        MatchData::from_raw(data, None)
+    }
+
+    /// allocate the block, and the heap frames used while matching, from `gctx`
+    pub fn new_in(pattern: &Pattern, gctx: &GeneralContext) -> Result<Self> {
+        let data =
+            unsafe { pcre2_match_data_create_from_pattern_8(pattern.as_ptr(), gctx.as_mut_ptr()) };
+        let data = check_alloc(data, "match data block")?;
+        Ok(MatchData::from_raw(data, Some(gctx.clone())))
+    }
+
+    /// a block holding `pairs` ovector pairs, the whole match included,
+    /// it can be shared by any pattern with fewer than `pairs` capture groups
+    pub fn with_capacity(pairs: u32) -> Self {
+        let data = unsafe { pcre2_match_data_create_8(pairs, ptr::null_mut()) };
+        assert!(!data.is_null(), "failed to allocate match data block");
+        MatchData::from_raw(data, None)
+    }
+
+    pub fn with_capacity_in(pairs: u32, gctx: &GeneralContext) -> Result<Self> {
+        let data = unsafe { pcre2_match_data_create_8(pairs, gctx.as_mut_ptr()) };
+        let data = check_alloc(data, "match data block")?;
+        Ok(MatchData::from_raw(data, Some(gctx.clone())))
+    }
+
+    fn from_raw(data: *mut pcre2_match_data_8, gctx: Option<GeneralContext>) -> Self {
         let ovector_ptr = unsafe { pcre2_get_ovector_pointer_8(data) };
 
         assert!(!ovector_ptr.is_null(), "null ovector pointer");
@@ -169,6 +259,7 @@ impl MatchData {
             data,
             ovector_ptr,
             ovector_cnt,
+            gctx,
         }
     }
 
@@ -181,60 +272,110 @@ impl MatchData {
         // the creation of a valid match data block.
         unsafe { slice::from_raw_parts(self.ovector_ptr, self.ovector_cnt as usize * 2) }
     }
+
+    /// the number of ovector pairs
+    pub fn capacity(&self) -> u32 {
+        self.ovector_cnt
+    }
+
+    /// whether every capture group of `pattern` fits in the ovector
+    pub fn fits(&self, pattern: &Pattern) -> bool {
+        pattern
+            .capture_count()
+            .is_ok_and(|count| count < self.ovector_cnt)
+    }
+
+    /// the size of the block in bytes, heap frames not included
+    pub fn size(&self) -> usize {
+        unsafe { pcre2_get_match_data_size_8(self.data) }
+    }
+
+    /// the heap frame memory kept by the block after the last match,
+    /// it only grows, until [`MatchData::reset`]
+    pub fn heapframes_size(&self) -> usize {
+        unsafe { pcre2_get_match_data_heapframes_size_8(self.data) }
+    }
+
+    /// release the retained heap frames by recreating the block with the
+    /// same capacity, e.g. after a pathological subject. The ovector is cleared.
+    pub fn reset(&mut self) -> Result<()> {
+        let gctx = self
+            .gctx
+            .as_ref()
+            .map_or(ptr::null_mut(), |g| g.as_mut_ptr());
+        let data = unsafe { pcre2_match_data_create_8(self.ovector_cnt, gctx) };
+        let data = check_alloc(data, "match data block")?;
+        // the old block is freed when dropped
+        let _old = std::mem::replace(self, MatchData::from_raw(data, self.gctx.clone()));
+        Ok(())
+    }
 }
 
-pub struct Matches<'p, 's> {
-    re: &'p PCRE2,
-    data: &'p MatchData,
-    subject: &'s [u8],
-    last_end: usize,
-    last_match: Option<usize>,
+/// Match time limits, see `pcre2_set_*_limit`.
+pub struct MatchContext(*mut pcre2_match_context_8, Option<GeneralContext>);
+
+impl MatchContext {
+    // panic when allocate failed
+    pub fn new() -> Self {
+        let ctx = unsafe { pcre2_match_context_create_8(ptr::null_mut()) };
+        assert!(!ctx.is_null(), "context allocate fail");
+        Self(ctx, None)
+    }
+
+    pub fn new_in(gctx: &GeneralContext) -> Result<Self> {
+        let ctx = unsafe { pcre2_match_context_create_8(gctx.as_mut_ptr()) };
+        let ctx = check_alloc(ctx, "match context")?;
+        Ok(Self(ctx, Some(gctx.clone())))
+    }
+
+    /// the heap limit in KiB for the backtracking frames
+    pub fn set_heap_limit(&mut self, kib: u32) {
+        unsafe { pcre2_set_heap_limit_8(self.0, kib) };
+    }
+
+    pub fn set_match_limit(&mut self, limit: u32) {
+        unsafe { pcre2_set_match_limit_8(self.0, limit) };
+    }
+
+    pub fn set_depth_limit(&mut self, limit: u32) {
+        unsafe { pcre2_set_depth_limit_8(self.0, limit) };
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut pcre2_match_context_8 {
+        self.0
+    }
 }
 
-impl<'r, 's> Iterator for Matches<'r, 's> {
-    type Item = Result<Match<'s>>;
+// SAFETY: owned like `MatchData`, only read while matching.
+unsafe impl Send for MatchContext {}
 
-    fn next(&mut self) -> Option<Result<Match<'s>>> {
-        if self.last_end > self.subject.len() {
-            return None;
-        }
-        let res = self.re.find_at(self.subject, self.last_end);
-        let m = match res {
-            Err(err) => {
-                if err.to_string().eq("No match items") {
-                    return None;
-                }
-                return Some(Err(err));
-            }
-            Ok(m) => m,
-        };
-        if m.start() == m.end() {
-            // This is an empty match. To ensure we make progress, start
-            // the next search at the smallest possible starting position
-            // of the next match following this one.
-            self.last_end = m.end() + 1;
-            // Don't accept empty matches immediately following a match.
-            // Just move on to the next match.
-            if Some(m.end()) == self.last_match {
-                return self.next();
-            }
-        } else {
-            self.last_end = m.end();
-        }
-        self.last_match = Some(m.end());
-        Some(Ok(m))
+impl Default for MatchContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MatchContext {
+    fn drop(&mut self) {
+        unsafe { pcre2_match_context_free_8(self.0) }
     }
 }
 
 pub struct PCRE2 {
     /// compile options
     options: u32,
+    /// compile extra options, set by the compile context
+    extra_options: u32,
     /// origin pattern string
     origin: String,
-    /// compiled pcre2 pattern
-    pattern: Pattern,
+    /// compiled pcre2 pattern, shared by [`PCRE2::share`]
+    pattern: Arc<Pattern>,
     /// match data used by pcre2 during matching
     data: MatchData,
+    /// match context, null when not set
+    context: Option<MatchContext>,
+    /// literal scan run before matching, none when disabled or nothing found
+    prefilter: Option<Prefilter>,
 }
 
 impl PCRE2 {
@@ -242,18 +383,45 @@ impl PCRE2 {
         PCRE2Builder::new().build(pattern)
     }
 
-    /// default NO_UTF_CHECK match mod
-    /// and just do one match, match all see [`find_iter`]
-    pub fn find_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Match<'s>> {
-        self.find_at_with_options(subject, start, PCRE2_NO_UTF_CHECK)
-    }
-
+    /// same as [`Matcher::find_at`], with match `options` rather than
+    /// `PCRE2_NO_UTF_CHECK`, no match is an error
     pub fn find_at_with_options<'s>(
         &self,
         subject: &'s [u8],
         start: usize,
         options: u32,
     ) -> Result<Match<'s>> {
+        self.match_in(&self.data, subject, start, options)?
+            .ok_or_else(|| anyhow!("No match items"))
+    }
+
+    /// same as [`Matcher::find_at`], but with an outside match data block,
+    /// so one block can be reused by many patterns in a hot loop
+    pub fn find_at_with_data<'s>(
+        &self,
+        data: &MatchData,
+        subject: &'s [u8],
+        start: usize,
+    ) -> Result<Match<'s>> {
+        if !data.fits(&self.pattern) {
+            bail!("match data too small: {} pairs", data.capacity());
+        }
+        self.match_in(data, subject, start, PCRE2_NO_UTF_CHECK)?
+            .ok_or_else(|| anyhow!("No match items"))
+    }
+
+    /// run `pcre2_match`, the ovector of `data` is set on match
+    fn match_in<'s>(
+        &self,
+        data: &MatchData,
+        subject: &'s [u8],
+        start: usize,
+        options: u32,
+    ) -> Result<Option<Match<'s>>> {
+        // other options, e.g. a partial match, may match without the literals
+        if options & !PCRE2_NO_UTF_CHECK == 0 && !self.may_match_at(subject, start) {
+            return Ok(None);
+        }
         let rc = unsafe {
             pcre2_match_8(
                 self.pattern.as_ptr(),
@@ -261,50 +429,166 @@ impl PCRE2 {
                 subject.len(),
                 start,
                 options,
-                self.data.as_mut_ptr(),
-                ptr::null_mut(),
+                data.as_mut_ptr(),
+                self.context
+                    .as_ref()
+                    .map_or(ptr::null_mut(), MatchContext::as_mut_ptr),
             )
         };
         if rc == PCRE2_ERROR_NOMATCH {
             // no match
-            Err(anyhow!("No match items"))
+            Ok(None)
         } else if rc > 0 {
             // match successfully
-            let ovector = self.data.ovector();
+            let ovector = data.ovector();
             let (start, end) = (ovector[0], ovector[1]);
-            Ok(Match {
-                subject: &subject[start..end],
-                start,
-                end,
-            })
+            Ok(Some(Match::new(&subject[start..end], start, end)))
         } else {
-            // since we create match data always with
-            // pcre2_match_data_create_from_pattern, so the
-            // ovector should big enough
+            // match data is either created from the pattern,
+            // or checked to fit it, so the ovector should big enough
             assert!(rc != 0);
             // other error handle
-            return Err(anyhow!("find error"));
+            Err(anyhow!("find error: {}", rc))
         }
     }
 
-    pub fn find_iter<'p, 's>(&'p self, subject: &'s [u8]) -> Matches<'p, 's> {
-        Matches {
-            re: self,
-            data: &self.data,
-            subject,
-            last_end: 0,
-            last_match: None,
+    pub fn find_iter<'p, 's>(&'p self, subject: &'s [u8]) -> Matches<'p, 's, PCRE2> {
+        Matches::new(self, subject)
+    }
+
+    /// the origin pattern string
+    pub fn as_str(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn options(&self) -> u32 {
+        self.options
+    }
+
+    pub fn extra_options(&self) -> u32 {
+        self.extra_options
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// a matcher of the same compiled pattern, without compiling it again,
+    /// with a match data block of its own, e.g. for another thread
+    pub fn share(&self) -> Result<PCRE2> {
+        let (data, context) = match_blocks(&self.pattern)?;
+        Ok(PCRE2 {
+            options: self.options,
+            extra_options: self.extra_options,
+            origin: self.origin.clone(),
+            pattern: Arc::clone(&self.pattern),
+            data,
+            context,
+            prefilter: self.prefilter,
+        })
+    }
+
+    pub fn prefilter(&self) -> Option<&Prefilter> {
+        self.prefilter.as_ref()
+    }
+
+    /// false if the literal prefilter rules out any match in `chunk`,
+    /// so callers can skip whole chunks without matching
+    pub fn may_match(&self, chunk: &[u8]) -> bool {
+        self.may_match_at(chunk, 0)
+    }
+
+    fn may_match_at(&self, subject: &[u8], start: usize) -> bool {
+        self.prefilter
+            .as_ref()
+            .is_none_or(|filter| filter.may_match(subject, start))
+    }
+}
+
+/// workspace ints for `pcre2_dfa_match`, the docs ask for at least 20
+const DFA_WORKSPACE: usize = 1000;
+
+impl Matcher for PCRE2 {
+    fn find_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Match<'s>>> {
+        self.match_in(&self.data, subject, start, PCRE2_NO_UTF_CHECK)
+    }
+
+    fn captures_at<'s>(&self, subject: &'s [u8], start: usize) -> Result<Option<Captures<'s>>> {
+        if self
+            .match_in(&self.data, subject, start, PCRE2_NO_UTF_CHECK)?
+            .is_none()
+        {
+            return Ok(None);
         }
+        let groups = self.pattern.capture_count()? as usize + 1;
+        let locations = self.data.ovector()[..groups * 2]
+            .chunks(2)
+            .map(|pair| match pair {
+                [PCRE2_UNSET, _] => None,
+                [start, end] => Some((*start, *end)),
+                _ => unreachable!(),
+            })
+            .collect();
+        Ok(Some(Captures::new(subject, locations)))
     }
 
-    pub fn is_match(&self, subject: &[u8]) -> bool {
-        self.find_at(subject, 0).is_ok()
+    /// the DFA algorithm with `PCRE2_DFA_SHORTEST` stops at the first match end,
+    /// patterns it can't run (e.g. back references) fall back to [`Matcher::find_at`]
+    fn shortest_match(&self, subject: &[u8]) -> Result<Option<usize>> {
+        if !self.may_match(subject) {
+            return Ok(None);
+        }
+        let mut workspace = vec![0 as libc::c_int; DFA_WORKSPACE];
+        let rc = unsafe {
+            pcre2_dfa_match_8(
+                self.pattern.as_ptr(),
+                subject.as_ptr(),
+                subject.len(),
+                0,
+                PCRE2_NO_UTF_CHECK | PCRE2_DFA_SHORTEST,
+                self.data.as_mut_ptr(),
+                self.context
+                    .as_ref()
+                    .map_or(ptr::null_mut(), MatchContext::as_mut_ptr),
+                workspace.as_mut_ptr(),
+                workspace.len(),
+            )
+        };
+        match rc {
+            PCRE2_ERROR_NOMATCH => Ok(None),
+            // the ovector holds the longest match first, the only one here
+            rc if rc >= 0 => Ok(Some(self.data.ovector()[1])),
+            // items the DFA algorithm doesn't support, or too much for its
+            // workspace, the usual algorithm copes
+            PCRE2_ERROR_DFA_UITEM
+            | PCRE2_ERROR_DFA_UCOND
+            | PCRE2_ERROR_DFA_UFUNC
+            | PCRE2_ERROR_DFA_UINVALID_UTF
+            | PCRE2_ERROR_DFA_RECURSE
+            | PCRE2_ERROR_DFA_WSSIZE => Ok(self.find_at(subject, 0)?.map(|m| m.end())),
+            rc => Err(anyhow!("dfa match error: {}", rc)),
+        }
     }
 }
 
+/// the match data block and match context of `pattern`, from its general
+/// context if it has one
+fn match_blocks(pattern: &Pattern) -> Result<(MatchData, Option<MatchContext>)> {
+    Ok(match &pattern.gctx {
+        Some(gctx) => (
+            MatchData::new_in(pattern, gctx)?,
+            Some(MatchContext::new_in(gctx)?),
+        ),
+        None => (MatchData::new(pattern), None),
+    })
+}
+
 #[derive(Default, Debug)]
 pub struct PCRE2Builder {
     options: u32,
+    extra_options: u32,
+    gctx: Option<GeneralContext>,
+    no_prefilter: bool,
 }
 
 impl PCRE2Builder {
@@ -315,13 +599,26 @@ impl PCRE2Builder {
     pub fn build(self, pattern: &str) -> Result<PCRE2> {
         // create pattern with compile options, default: 0x00000000
         let origin = pattern.to_string();
-        let pattern = Pattern::new_with(pattern, self.options, CompileContext::new())?;
-        let data = MatchData::new(&pattern);
+        let mut ctx = match &self.gctx {
+            Some(gctx) => CompileContext::new_in(gctx)?,
+            None => CompileContext::new(),
+        };
+        ctx.set_extra_options(self.extra_options)?;
+        let pattern = Pattern::new_with(pattern, self.options, ctx)?;
+        let (data, context) = match_blocks(&pattern)?;
+        let prefilter = if self.no_prefilter {
+            None
+        } else {
+            Some(Prefilter::new(&pattern)?).filter(|f| !f.is_empty())
+        };
         Ok(PCRE2 {
             options: self.options,
+            extra_options: self.extra_options,
             origin,
-            pattern,
+            pattern: Arc::new(pattern),
             data,
+            context,
+            prefilter,
         })
     }
 
@@ -335,6 +632,24 @@ impl PCRE2Builder {
         self.options |= option;
         self
     }
+
+    /// the `PCRE2_EXTRA_*` options, checked when building
+    pub fn extra_options(mut self, options: u32) -> Self {
+        self.extra_options = options;
+        self
+    }
+
+    /// scan for the required literals before matching, on by default
+    pub fn prefilter(mut self, enable: bool) -> Self {
+        self.no_prefilter = !enable;
+        self
+    }
+
+    /// allocate the pattern, match data and match context from `gctx`
+    pub fn general_context(mut self, gctx: GeneralContext) -> Self {
+        self.gctx = Some(gctx);
+        self
+    }
 }
 
 #[cfg(test)]
@@ -362,4 +677,94 @@ mod tests {
         let pattern = Pattern::new(r"(?<=\d{4})[^\d\s]{3,11}(?=\S)");
         assert!(pattern.is_ok());
     }
+
+    #[test]
+    fn test_pattern_size() {
+        let small = Pattern::new(r"a").unwrap();
+        let large = Pattern::new(r"(?<=\d{4})[^\d\s]{3,11}(?=\S)").unwrap();
+        assert!(small.size().unwrap() > 0);
+        assert!(large.size().unwrap() > small.size().unwrap());
+    }
+
+    #[test]
+    fn test_match_data_reuse() {
+        let one = PCRE2::new(r"(\d{4})-(\d{2})").unwrap();
+        let two = PCRE2::new(r"([a-z]+)").unwrap();
+        let mut data = MatchData::with_capacity(3);
+        assert!(data.fits(&one.pattern) && data.fits(&two.pattern));
+        assert!(data.size() > 0);
+
+        let m = one.find_at_with_data(&data, b"on 2023-10", 0).unwrap();
+        assert_eq!((m.start(), m.end()), (3, 10));
+        assert_eq!(&data.ovector()[2..6], &[3, 7, 8, 10]);
+        let m = two.find_at_with_data(&data, b"2023 abc", 0).unwrap();
+        assert_eq!(m.as_bytes(), b"abc");
+
+        let small = MatchData::with_capacity(1);
+        assert!(one.find_at_with_data(&small, b"2023-10", 0).is_err());
+
+        // a backtracking heavy subject leaves heap frames behind
+        let heavy = PCRE2::new(r"(a|b|ab)*c").unwrap();
+        let subject = "ab".repeat(2000);
+        assert!(heavy
+            .find_at_with_data(&data, subject.as_bytes(), 0)
+            .is_err());
+        assert!(data.heapframes_size() > 0);
+        data.reset().unwrap();
+        assert_eq!(data.heapframes_size(), 0);
+        assert_eq!(data.capacity(), 3);
+    }
+
+    #[test]
+    fn test_matcher_captures() {
+        let re = PCRE2::new(r"(\d{4})-(\d{2})?(x)?").unwrap();
+        let caps = Matcher::captures_at(&re, b"at 2023-", 0).unwrap().unwrap();
+        assert_eq!(caps.len(), 4);
+        assert_eq!(caps.get(0).unwrap().as_bytes(), b"2023-");
+        assert_eq!(caps.get(1).unwrap().as_bytes(), b"2023");
+        assert!(caps.get(2).is_none() && caps.get(3).is_none());
+        assert!(Matcher::captures_at(&re, b"none", 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_share_across_threads() {
+        let re = PCRE2::new(r"(\d{4})-(\d{2})").unwrap();
+        let shared: Vec<_> = (0..4).map(|_| re.share().unwrap()).collect();
+        assert!(ptr::eq(re.pattern(), shared[0].pattern()));
+        let handles: Vec<_> = shared
+            .into_iter()
+            .enumerate()
+            .map(|(i, re)| {
+                std::thread::spawn(move || {
+                    let subject = format!("{} on 2023-1{}", "x".repeat(i), i);
+                    let m = re.find_at(subject.as_bytes(), 0).unwrap().unwrap();
+                    (m.start(), m.end())
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), (i + 4, i + 11));
+        }
+    }
+
+    #[test]
+    fn test_matcher_shortest_match() {
+        let re = PCRE2::new(r"abcd|ab").unwrap();
+        assert_eq!(re.find_at(b"xxabcd", 0).unwrap().unwrap().end(), 6);
+        assert_eq!(re.shortest_match(b"xxabcd").unwrap(), Some(4));
+        assert_eq!(re.shortest_match(b"xxa").unwrap(), None);
+        // back references aren't supported by the DFA algorithm
+        let re = PCRE2::new(r"(a+)\1").unwrap();
+        assert_eq!(re.shortest_match(b"baaaa").unwrap(), Some(5));
+    }
+
+    #[test]
+    fn test_builder_extra_options() {
+        let re = PCRE2Builder::new()
+            .extra_options(PCRE2_EXTRA_MATCH_WORD)
+            .build("foo")
+            .unwrap();
+        assert!(re.is_match(b"a foo b").unwrap());
+        assert!(!re.is_match(b"afoob").unwrap());
+    }
 }
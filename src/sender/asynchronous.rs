@@ -0,0 +1,470 @@
+//! [`AsyncSender`], the async counterpart of [`Sender`], for embedding the
+//! filter in tokio applications.
+//!
+//! The transports behave like their blocking versions: [`AsyncUdp`] sends one
+//! datagram per result, [`AsyncTcp`] and [`AsyncUnixStream`] one frame and
+//! reconnect once when the peer went away. [`Blocking`] runs any blocking
+//! [`Sender`] on tokio's blocking pool.
+
+use super::conn::peer_closed;
+use super::{Framing, Metrics, Sender, Stats};
+use crate::encoder::{Encoded, Encoder, OwnedRecord, Record};
+use anyhow::{bail, Result};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+
+const AUTO_FD: &str = "0.0.0.0:0";
+
+pub trait AsyncSender: Sync {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// send a structured match, the matched bytes unless encoded
+    fn send_record(&self, record: &Record) -> impl Future<Output = Result<usize>> + Send {
+        async move { self.send(record.as_bytes()).await }
+    }
+
+    /// send whatever is buffered, e.g. on shutdown
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// the counters of the transport, see [`Sender::stats`]
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+}
+
+impl<S: AsyncSender, E: Encoder + Sync> AsyncSender for Encoded<S, E> {
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.get_ref().send(buf).await
+    }
+
+    async fn send_record(&self, record: &Record<'_>) -> Result<usize> {
+        let mut out = Vec::with_capacity(record.as_bytes().len() + 64);
+        self.encoder().encode(record, &mut out)?;
+        self.get_ref().send(&out).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.get_ref().flush().await
+    }
+
+    fn stats(&self) -> Stats {
+        self.get_ref().stats()
+    }
+}
+
+/// Sends every result as one UDP datagram.
+pub struct AsyncUdp {
+    socket: UdpSocket,
+    metrics: Metrics,
+}
+
+impl AsyncUdp {
+    pub async fn new(addr: &str) -> Result<Self> {
+        let socket = UdpSocket::bind(AUTO_FD).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            metrics: Metrics::new(),
+        })
+    }
+}
+
+impl AsyncSender for AsyncUdp {
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let send = async { Ok(self.socket.send(buf).await?) };
+        self.metrics.record_async(send).await
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+/// Sends every result as one frame over a TCP connection, reconnecting once
+/// when the peer went away.
+pub struct AsyncTcp {
+    addr: String,
+    framing: Framing,
+    /// `None` after the connection is lost
+    stream: Mutex<Option<TcpStream>>,
+    metrics: Metrics,
+}
+
+impl AsyncTcp {
+    /// connect right away, results are length prefixed by default
+    pub async fn new(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            addr: addr.to_string(),
+            framing: Framing::default(),
+            stream: Mutex::new(Some(stream)),
+            metrics: Metrics::new(),
+        })
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    async fn send_frame(&self, buf: &[u8]) -> Result<usize> {
+        let mut frame = Vec::with_capacity(buf.len() + 4);
+        self.framing.encode(buf, &mut frame)?;
+
+        let mut stream = self.stream.lock().await;
+        let mut retried = false;
+        loop {
+            let mut conn = match stream.take() {
+                Some(conn) if !peer_closed(&conn) => conn,
+                _ => self.connect().await?,
+            };
+            match conn.write_all(&frame).await {
+                Ok(()) => {
+                    *stream = Some(conn);
+                    return Ok(buf.len());
+                }
+                Err(e) if retried => bail!("tcp send error: {}", e),
+                Err(_) => {
+                    retried = true;
+                    self.metrics.retry();
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSender for AsyncTcp {
+    /// returns the payload length, a frame is either written whole or an error
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record_async(self.send_frame(buf)).await
+    }
+
+    /// reconnects count as retries
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use crate::sender::conn::is_absent;
+    use crate::sender::UnixAddr;
+    use std::os::unix::net::{self, SocketAddr};
+    use std::path::PathBuf;
+    use tokio::net::{UnixDatagram, UnixStream};
+
+    /// the path tokio connects to, an abstract name behind a NUL
+    fn connect_path(addr: &UnixAddr) -> PathBuf {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        match addr {
+            UnixAddr::Path(path) => path.clone(),
+            UnixAddr::Abstract(name) => OsStr::from_bytes(&[&[0], &name[..]].concat()).into(),
+        }
+    }
+
+    /// Sends every result as one frame over a `SOCK_STREAM` unix socket,
+    /// connecting on the first send and again when the peer went away.
+    pub struct AsyncUnixStream {
+        path: PathBuf,
+        framing: Framing,
+        stream: Mutex<Option<UnixStream>>,
+        metrics: Metrics,
+    }
+
+    impl AsyncUnixStream {
+        /// a path, or on Linux `@name` for the abstract namespace
+        pub fn new(addr: &str) -> Result<Self> {
+            let addr = UnixAddr::parse(addr)?;
+            // e.g. too long, found out now rather than on the first send
+            addr.to_socket_addr()?;
+            Ok(Self {
+                path: connect_path(&addr),
+                framing: Framing::default(),
+                stream: Mutex::new(None),
+                metrics: Metrics::new(),
+            })
+        }
+
+        pub fn framing(mut self, framing: Framing) -> Self {
+            self.framing = framing;
+            self
+        }
+
+        async fn connect(&self) -> Result<UnixStream> {
+            Ok(UnixStream::connect(&self.path).await?)
+        }
+    }
+
+    impl AsyncUnixStream {
+        async fn send_frame(&self, buf: &[u8]) -> Result<usize> {
+            let mut frame = Vec::with_capacity(buf.len() + 4);
+            self.framing.encode(buf, &mut frame)?;
+
+            let mut stream = self.stream.lock().await;
+            let mut retried = false;
+            loop {
+                let mut conn = match stream.take() {
+                    Some(conn) if !peer_closed(&conn) => conn,
+                    _ => self.connect().await?,
+                };
+                match conn.write_all(&frame).await {
+                    Ok(()) => {
+                        *stream = Some(conn);
+                        return Ok(buf.len());
+                    }
+                    Err(e) if retried => bail!("unix stream send error: {}", e),
+                    Err(_) => {
+                        retried = true;
+                        self.metrics.retry();
+                    }
+                }
+            }
+        }
+    }
+
+    impl AsyncSender for AsyncUnixStream {
+        async fn send(&self, buf: &[u8]) -> Result<usize> {
+            self.metrics.record_async(self.send_frame(buf)).await
+        }
+
+        fn stats(&self) -> Stats {
+            self.metrics.stats()
+        }
+    }
+
+    /// Sends every result as one `SOCK_DGRAM` datagram.
+    pub struct AsyncUnixDatagram {
+        addr: SocketAddr,
+        socket: Mutex<Option<UnixDatagram>>,
+        metrics: Metrics,
+    }
+
+    impl AsyncUnixDatagram {
+        pub fn new(addr: &str) -> Result<Self> {
+            Ok(Self {
+                addr: UnixAddr::parse(addr)?.to_socket_addr()?,
+                socket: Mutex::new(None),
+                metrics: Metrics::new(),
+            })
+        }
+
+        fn connect(&self) -> Result<UnixDatagram> {
+            let socket = net::UnixDatagram::unbound()?;
+            socket.connect_addr(&self.addr)?;
+            socket.set_nonblocking(true)?;
+            Ok(UnixDatagram::from_std(socket)?)
+        }
+    }
+
+    impl AsyncUnixDatagram {
+        async fn send_datagram(&self, buf: &[u8]) -> Result<usize> {
+            let mut socket = self.socket.lock().await;
+            if let Some(conn) = socket.as_ref() {
+                match conn.send(buf).await {
+                    Ok(len) => return Ok(len),
+                    // the socket file may be recreated, connect to it again
+                    Err(e) if is_absent(&e) => *socket = None,
+                    Err(e) => bail!(e),
+                }
+            }
+            let conn = self.connect()?;
+            let len = conn.send(buf).await?;
+            *socket = Some(conn);
+            Ok(len)
+        }
+    }
+
+    impl AsyncSender for AsyncUnixDatagram {
+        async fn send(&self, buf: &[u8]) -> Result<usize> {
+            self.metrics.record_async(self.send_datagram(buf)).await
+        }
+
+        fn stats(&self) -> Stats {
+            self.metrics.stats()
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::*;
+
+/// Runs a blocking [`Sender`] on tokio's blocking pool, so it doesn't stall
+/// the runtime. Every call copies its payload to move it there.
+pub struct Blocking<S> {
+    sender: Arc<S>,
+}
+
+impl<S: Sender + Send + Sync + 'static> Blocking<S> {
+    pub fn new(sender: S) -> Self {
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let sender = Arc::clone(&self.sender);
+        tokio::task::spawn_blocking(move || f(&sender)).await?
+    }
+}
+
+impl<S: Sender + Send + Sync + 'static> AsyncSender for Blocking<S> {
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let buf = buf.to_vec();
+        self.run(move |sender| sender.send(&buf)).await
+    }
+
+    async fn send_record(&self, record: &Record<'_>) -> Result<usize> {
+        let record = OwnedRecord::from(record);
+        self.run(move |sender| sender.send_record(&record.as_record()))
+            .await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.run(|sender| sender.flush()).await
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Format;
+    use crate::matcher::Match;
+    use crate::sender::StdoutSink;
+    use std::io::BufReader;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn test_async_udp() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        block_on(async {
+            let udp = AsyncUdp::new(&addr).await.unwrap();
+            assert_eq!(udp.send(b"abc").await.unwrap(), 3);
+            let stats = Encoded::new(udp, Format::Raw).stats();
+            assert_eq!((stats.messages, stats.bytes), (1, 3));
+        });
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"abc");
+    }
+
+    #[test]
+    fn test_async_tcp_encoded() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        block_on(async {
+            let tcp = AsyncTcp::new(&addr).await.unwrap();
+            let sender = Encoded::new(tcp, Format::Csv);
+            let m = Match::new(b"2023", 3, 7);
+            let record = Record::new(1, "app.log", m);
+            sender.send_record(&record).await.unwrap();
+        });
+        let (conn, _) = listener.accept().unwrap();
+        let frame = Framing::LengthPrefix
+            .decode(&mut BufReader::new(conn))
+            .unwrap()
+            .unwrap();
+        assert!(frame.starts_with(b"1,app.log,3,7,"));
+        assert!(frame.ends_with(b",2023"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_async_unix_datagram() {
+        let path = std::env::temp_dir().join(format!("async-dgram-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        block_on(async {
+            let sender = AsyncUnixDatagram::new(path.to_str().unwrap()).unwrap();
+            sender.send(b"unix").await.unwrap();
+        });
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"unix");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_async_unix_stream() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("async-stream-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sender = AsyncUnixStream::new(path.to_str().unwrap()).unwrap();
+        assert!(block_on(sender.send(b"early")).is_err());
+        let listener = UnixListener::bind(&path).unwrap();
+        block_on(sender.send(b"abc")).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let frame = Framing::LengthPrefix
+            .decode(&mut BufReader::new(conn))
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame, b"abc");
+        std::fs::remove_file(&path).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("@xipin-async-{}", std::process::id());
+            let addr = crate::sender::UnixAddr::parse(&name).unwrap();
+            let listener = UnixListener::bind_addr(&addr.to_socket_addr().unwrap()).unwrap();
+            let sender = AsyncUnixStream::new(&name).unwrap();
+            block_on(sender.send(b"abstract")).unwrap();
+            let (conn, _) = listener.accept().unwrap();
+            let frame = Framing::LengthPrefix
+                .decode(&mut BufReader::new(conn))
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame, b"abstract");
+        }
+    }
+
+    #[test]
+    fn test_blocking_adapter() {
+        let sink = StdoutSink::with_writer(Vec::new());
+        let sender = Blocking::new(sink);
+        block_on(async {
+            sender.send(b"abc").await.unwrap();
+            let m = Match::new(b"def", 0, 3);
+            sender.send_record(&Record::new(0, "-", m)).await.unwrap();
+            sender.flush().await.unwrap();
+            assert_eq!(AsyncSender::stats(&sender).messages, 2);
+        });
+        let sink = Arc::try_unwrap(sender.sender).ok().unwrap();
+        assert_eq!(sink.into_inner(), b"abc\ndef\n");
+    }
+}
@@ -0,0 +1,263 @@
+//! Packs many records into one payload, e.g. one UDP datagram.
+//!
+//! A batch is records framed with [`Framing::LengthPrefix`] back to back, the
+//! receiver splits it again with [`unbatch`].
+
+use super::{Framing, Sender, Stats};
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// an Ethernet MTU of 1500, less the IPv4 and UDP headers
+pub const UDP_MTU_PAYLOAD: usize = 1472;
+
+/// length prefix of every record in a batch
+const PREFIX: usize = 4;
+
+struct Batch {
+    buf: Vec<u8>,
+    /// when the first record of the batch came in
+    since: Option<Instant>,
+}
+
+struct Shared<S> {
+    sender: S,
+    /// atomic, the flush thread of [`Batched::max_delay`] shares it already
+    max_payload: AtomicUsize,
+    batch: Mutex<Batch>,
+}
+
+impl<S: Sender> Shared<S> {
+    fn flush(&self) -> Result<()> {
+        let mut batch = self.batch.lock().unwrap();
+        self.flush_batch(&mut batch)
+    }
+
+    fn flush_batch(&self, batch: &mut Batch) -> Result<()> {
+        batch.since = None;
+        if batch.buf.is_empty() {
+            return Ok(());
+        }
+        // the batch is gone even if sending fails, like a lost datagram
+        let sent = self.sender.send(&batch.buf);
+        let len = batch.buf.len();
+        batch.buf.clear();
+        match sent? {
+            sent if sent < len => bail!("short batch send: {} of {} bytes", sent, len),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A sender that accumulates records up to `max_payload` bytes, or for at most
+/// `max_delay`, before handing them to `S` as one payload.
+///
+/// Call [`Sender::flush`] on shutdown, dropping it flushes too but loses the error.
+pub struct Batched<S: Sender> {
+    shared: Arc<Shared<S>>,
+}
+
+impl<S: Sender> Batched<S> {
+    /// batches up to [`UDP_MTU_PAYLOAD`], flushed when full or by hand
+    pub fn new(sender: S) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                sender,
+                max_payload: AtomicUsize::new(UDP_MTU_PAYLOAD),
+                batch: Mutex::new(Batch {
+                    buf: Vec::with_capacity(UDP_MTU_PAYLOAD),
+                    since: None,
+                }),
+            }),
+        }
+    }
+
+    pub fn max_payload(self, max_payload: usize) -> Self {
+        self.shared
+            .max_payload
+            .store(max_payload, Ordering::Relaxed);
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.shared.sender
+    }
+}
+
+impl<S: Sender + Send + Sync + 'static> Batched<S> {
+    /// also flush a batch once its first record is `max_delay` old,
+    /// checked by a background thread that ends with the sender
+    pub fn max_delay(self, max_delay: Duration) -> Self {
+        let shared = Arc::downgrade(&self.shared);
+        let tick = (max_delay / 4).max(Duration::from_millis(1));
+        thread::spawn(move || flush_loop(shared, max_delay, tick));
+        self
+    }
+}
+
+fn flush_loop<S: Sender>(shared: Weak<Shared<S>>, max_delay: Duration, tick: Duration) {
+    loop {
+        thread::sleep(tick);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut batch = shared.batch.lock().unwrap();
+        if batch
+            .since
+            .is_some_and(|since| since.elapsed() >= max_delay)
+        {
+            // nobody to report to, the next send or flush sees a broken sender anyway
+            let _ = shared.flush_batch(&mut batch);
+        }
+    }
+}
+
+impl<S: Sender> Sender for Batched<S> {
+    /// returns the record length once it's in the batch, a record too large
+    /// for `max_payload` goes out in a batch of its own
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        let shared = &self.shared;
+        let max_payload = shared.max_payload.load(Ordering::Relaxed);
+        let mut batch = shared.batch.lock().unwrap();
+        if !batch.buf.is_empty() && batch.buf.len() + PREFIX + buf.len() > max_payload {
+            shared.flush_batch(&mut batch)?;
+        }
+        Framing::LengthPrefix.encode(buf, &mut batch.buf)?;
+        if batch.since.is_none() {
+            batch.since = Some(Instant::now());
+        }
+        if batch.buf.len() >= max_payload {
+            shared.flush_batch(&mut batch)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.flush()?;
+        self.shared.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.shared.sender.stats()
+    }
+}
+
+impl<S: Sender> Drop for Batched<S> {
+    fn drop(&mut self) {
+        let _ = self.shared.flush();
+    }
+}
+
+/// split a batch into its records
+pub fn unbatch(mut payload: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut records = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < PREFIX {
+            bail!("truncated batch: {} bytes left", payload.len());
+        }
+        let (len, rest) = payload.split_at(PREFIX);
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if rest.len() < len {
+            bail!("truncated batch record: {} of {} bytes", rest.len(), len);
+        }
+        let (record, rest) = rest.split_at(len);
+        records.push(record);
+        payload = rest;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::StdoutSink;
+
+    /// collect every payload sent
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<Vec<u8>>>);
+
+    impl Sender for Collect {
+        fn send(&self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    fn payloads(batched: &Batched<Collect>) -> usize {
+        batched.get_ref().0.lock().unwrap().len()
+    }
+
+    #[test]
+    fn test_batched_max_payload() {
+        let batched = Batched::new(Collect::default()).max_payload(20);
+        batched.send(b"aaaa").unwrap();
+        batched.send(b"bbbb").unwrap();
+        assert_eq!(payloads(&batched), 0);
+        // 20 bytes would be exceeded
+        batched.send(b"cc").unwrap();
+        assert_eq!(payloads(&batched), 1);
+        // a large record goes alone
+        batched.send(&[b'x'; 32]).unwrap();
+        batched.flush().unwrap();
+
+        let sent = batched.get_ref().0.lock().unwrap().clone();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(unbatch(&sent[0]).unwrap(), vec![b"aaaa", b"bbbb"]);
+        assert_eq!(unbatch(&sent[1]).unwrap(), vec![&b"cc"[..]]);
+        assert_eq!(unbatch(&sent[2]).unwrap(), vec![&[b'x'; 32][..]]);
+    }
+
+    #[test]
+    fn test_batched_max_delay() {
+        let batched = Batched::new(Collect::default()).max_delay(Duration::from_millis(5));
+        batched.send(b"late").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(payloads(&batched), 1);
+
+        // set once the flush thread runs
+        let batched = Batched::new(Collect::default())
+            .max_delay(Duration::from_secs(60))
+            .max_payload(8);
+        batched.send(b"full").unwrap();
+        assert_eq!(payloads(&batched), 1);
+    }
+
+    #[test]
+    fn test_batched_flush_on_drop() {
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()).delimiter(None));
+        let batched = Batched::new(Arc::clone(&sink));
+        batched.send(b"abc").unwrap();
+        drop(batched);
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        assert_eq!(sink.into_inner(), b"\x00\x00\x00\x03abc");
+    }
+
+    #[test]
+    fn test_batched_records() {
+        use crate::encoder::{Encoded, Format};
+
+        let subject = b"at 2023-04";
+        let record = crate::encoder::tests::record(subject);
+        // batched as its matched bytes
+        let batched = Batched::new(Collect::default());
+        batched.send_record(&record).unwrap();
+        batched.flush().unwrap();
+        let sent = batched.get_ref().0.lock().unwrap().clone();
+        assert_eq!(unbatch(&sent[0]).unwrap(), vec![b"2023"]);
+        let encoded = Encoded::new(batched, Format::Raw);
+        encoded.send_record(&record).unwrap();
+        encoded.flush().unwrap();
+        let sent = encoded.get_ref().get_ref().0.lock().unwrap().clone();
+        assert_eq!(unbatch(&sent[0]).unwrap(), vec![b"2023"]);
+    }
+
+    #[test]
+    fn test_unbatch_truncated() {
+        assert!(unbatch(b"\x00\x00").is_err());
+        assert!(unbatch(b"\x00\x00\x00\x05abc").is_err());
+        assert!(unbatch(b"").unwrap().is_empty());
+    }
+}
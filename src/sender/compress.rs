@@ -0,0 +1,197 @@
+//! Compression of every payload, e.g. the batches of [`super::Batched`].
+//!
+//! A payload starts with a flag byte, [`RAW`], [`GZIP`] or [`ZSTD`], so the
+//! receiver knows whether and how to decompress it with [`decompress`]. A
+//! payload that doesn't shrink goes out raw. gzip and zstd are behind the
+//! `gzip` and `zstd` features.
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use super::MAX_FRAME;
+use super::{Sender, Stats};
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+/// the flag of a payload sent as is
+pub const RAW: u8 = 0;
+pub const GZIP: u8 = 1;
+pub const ZSTD: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// deflate level, 0 to 9
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+    /// zstd level, 1 to 22, 0 for its default
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// `buf` compressed, after its flag byte
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => {
+                use std::io::Write;
+                let level = flate2::Compression::new(level);
+                let mut encoder = flate2::write::GzEncoder::new(vec![GZIP], level);
+                encoder.write_all(buf)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let mut out = vec![ZSTD];
+                zstd::stream::copy_encode(buf, &mut out, level)?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// A sender that compresses every payload for `S`.
+pub struct Compressed<S: Sender> {
+    sender: S,
+    compression: Compression,
+}
+
+impl<S: Sender> Compressed<S> {
+    pub fn new(sender: S, compression: Compression) -> Self {
+        Self {
+            sender,
+            compression,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+}
+
+impl<S: Sender> Sender for Compressed<S> {
+    /// returns the length of `buf` once it's sent, compressed or not
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        let mut out = self.compression.compress(buf)?;
+        if out.len() > buf.len() {
+            out.clear();
+            out.push(RAW);
+            out.extend_from_slice(buf);
+        }
+        match self.sender.send(&out)? {
+            sent if sent < out.len() => bail!("short send: {} of {} bytes", sent, out.len()),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+/// all of `reader`, an error past [`MAX_FRAME`] bytes rather than trusting
+/// whatever a small payload inflates to
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_bounded<R: std::io::Read>(reader: R) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    reader.take(MAX_FRAME as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > MAX_FRAME {
+        bail!("payload decompresses to more than {} bytes", MAX_FRAME);
+    }
+    Ok(out)
+}
+
+/// the payload of a [`Compressed`] sender, without its flag byte, at most
+/// [`MAX_FRAME`](super::MAX_FRAME) bytes once decompressed
+pub fn decompress(payload: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let Some((&flag, data)) = payload.split_first() else {
+        bail!("empty payload");
+    };
+    match flag {
+        RAW => Ok(Cow::Borrowed(data)),
+        #[cfg(feature = "gzip")]
+        GZIP => Ok(Cow::Owned(read_bounded(flate2::read::GzDecoder::new(
+            data,
+        ))?)),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(Cow::Owned(read_bounded(
+            zstd::stream::read::Decoder::with_buffer(data)?,
+        )?)),
+        #[cfg(not(feature = "gzip"))]
+        GZIP => bail!("gzip payload, but built without the gzip feature"),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => bail!("zstd payload, but built without the zstd feature"),
+        _ => bail!("unknown compression: {}", flag),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn compressions() -> Vec<(Compression, u8)> {
+        vec![
+            #[cfg(feature = "gzip")]
+            (Compression::Gzip(6), GZIP),
+            #[cfg(feature = "zstd")]
+            (Compression::Zstd(3), ZSTD),
+        ]
+    }
+
+    #[test]
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn test_compressed_round_trip() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Collect(Mutex<Vec<Vec<u8>>>);
+
+        impl Sender for Collect {
+            fn send(&self, buf: &[u8]) -> Result<usize> {
+                self.0.lock().unwrap().push(buf.to_vec());
+                Ok(buf.len())
+            }
+        }
+
+        let log = b"2023-04-25 05:20:00 ERROR disk full on /var\n".repeat(20);
+        for (compression, flag) in compressions() {
+            let compressed = Compressed::new(Collect::default(), compression);
+            assert_eq!(compressed.send(&log).unwrap(), log.len());
+            // too short to shrink
+            compressed.send(b"abc").unwrap();
+
+            let sent = compressed.get_ref().0.lock().unwrap();
+            assert_eq!(sent[0][0], flag);
+            assert!(sent[0].len() < log.len() / 4);
+            assert_eq!(decompress(&sent[0]).unwrap(), &log[..]);
+            assert_eq!(sent[1], b"\0abc");
+            assert_eq!(decompress(&sent[1]).unwrap(), &b"abc"[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn test_decompress_bomb() {
+        let zeros = vec![0; MAX_FRAME + 1];
+        for (compression, _) in compressions() {
+            let bomb = compression.compress(&zeros).unwrap();
+            assert!(bomb.len() < MAX_FRAME / 100);
+            assert!(decompress(&bomb).is_err());
+            let fits = compression.compress(&zeros[..MAX_FRAME]).unwrap();
+            assert_eq!(decompress(&fits).unwrap().len(), MAX_FRAME);
+        }
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        assert!(decompress(b"").is_err());
+        assert!(decompress(b"\x09abc").is_err());
+        assert!(decompress(b"\x01not gzip").is_err());
+        assert!(decompress(b"\x02not zstd").is_err());
+    }
+}
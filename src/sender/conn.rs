@@ -0,0 +1,39 @@
+//! Helpers shared by the connected senders, blocking and async.
+
+use std::io::{self, ErrorKind};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/// A write to a closed peer usually succeeds once before failing,
+/// so check for the end of stream first to not lose that frame.
+#[cfg(unix)]
+pub(super) fn peer_closed<S: AsRawFd>(stream: &S) -> bool {
+    let mut buf = [0u8; 1];
+    let rc = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match rc {
+        0 => true,
+        rc if rc > 0 => false,
+        _ => io::Error::last_os_error().kind() != ErrorKind::WouldBlock,
+    }
+}
+
+// the write error is relied on instead
+#[cfg(not(unix))]
+pub(super) fn peer_closed<S>(_stream: &S) -> bool {
+    false
+}
+
+/// whether the collector is just not there (yet), rather than misconfigured
+pub(super) fn is_absent(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+    )
+}
@@ -0,0 +1,412 @@
+//! Drops repeated results, so a token a log repeats thousands of times goes
+//! out once.
+//!
+//! The first result of a key is sent right away, later ones with the same key
+//! are counted instead while the key is remembered. When it's forgotten, the
+//! window is over or the LRU memory needs the room, the last duplicate goes
+//! out with [`Record::count`] set to how many were collapsed, if counting is
+//! on. It goes out as a record, the way the results it counts came, so it
+//! shows with an [`Encoded`](crate::encoder::Encoded) layer below. Forgotten
+//! keys are looked for on every send and all of them on flush, or drop. A
+//! count that fails to go out is lost, the send that found it still goes
+//! through and returns the error.
+//!
+//! Plain bytes are deduplicated by their exact value and never counted.
+
+use super::{Sender, Stats};
+use crate::encoder::{OwnedRecord, Record};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// the key of a record for [`DedupKey::Extract`]
+pub type KeyFn = dyn Fn(&Record) -> Option<Vec<u8>> + Send + Sync;
+
+/// What makes two results duplicates, on top of the same pattern.
+pub enum DedupKey {
+    /// the matched bytes
+    Exact,
+    /// capture group `n`, from 1, a result without it isn't deduplicated
+    Capture(usize),
+    /// `None` to not deduplicate a result
+    Extract(Box<KeyFn>),
+}
+
+impl DedupKey {
+    pub fn extract<F>(f: F) -> Self
+    where
+        F: Fn(&Record) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        DedupKey::Extract(Box::new(f))
+    }
+
+    fn of(&self, record: &Record) -> Option<Vec<u8>> {
+        match self {
+            DedupKey::Exact => Some(record.as_bytes().to_vec()),
+            DedupKey::Capture(group) => group
+                .checked_sub(1)
+                .and_then(|i| record.captures.get(i).copied().flatten())
+                .map(|m| m.as_bytes().to_vec()),
+            DedupKey::Extract(f) => f(record),
+        }
+    }
+}
+
+/// How long a key is remembered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Memory {
+    /// from the first result of the key on
+    Window(Duration),
+    /// as long as it's one of the last `n` keys seen
+    Lru(usize),
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::Window(WINDOW)
+    }
+}
+
+/// pattern id, `None` for plain bytes, and the key
+type Key = (Option<usize>, Vec<u8>);
+
+struct Entry {
+    first: Instant,
+    /// in `State::order`
+    tick: u64,
+    duplicates: u64,
+    last: Option<OwnedRecord>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    /// keys oldest first, by first seen in a window or last seen in an LRU
+    order: BTreeMap<u64, Key>,
+    next_tick: u64,
+    dropped: u64,
+}
+
+/// A sender that passes on only the first of repeated results to `S`.
+pub struct Dedup<S: Sender> {
+    sender: S,
+    key: DedupKey,
+    memory: Memory,
+    count: bool,
+    state: Mutex<State>,
+}
+
+impl<S: Sender> Dedup<S> {
+    /// exact matches within a minute, not counted
+    pub fn new(sender: S) -> Self {
+        Self {
+            sender,
+            key: DedupKey::Exact,
+            memory: Memory::default(),
+            count: false,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn key(mut self, key: DedupKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn memory(mut self, memory: Memory) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// send the last duplicate of a key with the count once it's forgotten
+    pub fn count(mut self, count: bool) -> Self {
+        self.count = count;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    /// duplicates dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// forget the keys out of memory, all of them if `all`, and send their
+    /// counts
+    fn forget(&self, state: &mut State, all: bool) -> Result<()> {
+        let mut first_error = None;
+        while let Some(entry) = state.order.first_entry() {
+            let expired = match self.memory {
+                Memory::Window(window) => state.entries[entry.get()].first.elapsed() >= window,
+                Memory::Lru(n) => state.entries.len() > n.max(1),
+            };
+            if !(all || expired) {
+                break;
+            }
+            let key = entry.remove();
+            let entry = state
+                .entries
+                .remove(&key)
+                .expect("ordered keys have entries");
+            // only records are counted, so the count is one too
+            if let (true, Some(last)) = (self.count, entry.last) {
+                let record = last.as_record().with_count(entry.duplicates);
+                if let Err(e) = self.sender.send_record(&record) {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// true if `key` is a duplicate, remembered along with `record`
+    fn seen(&self, state: &mut State, key: Key, record: Option<&Record>) -> bool {
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        match state.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.duplicates += 1;
+                if self.count {
+                    entry.last = record.map(OwnedRecord::from);
+                }
+                if let Memory::Lru(_) = self.memory {
+                    let key = state
+                        .order
+                        .remove(&entry.tick)
+                        .expect("entries are ordered");
+                    entry.tick = tick;
+                    state.order.insert(tick, key);
+                }
+                state.dropped += 1;
+                true
+            }
+            None => {
+                let entry = Entry {
+                    first: Instant::now(),
+                    tick,
+                    duplicates: 0,
+                    last: None,
+                };
+                state.order.insert(tick, key.clone());
+                state.entries.insert(key, entry);
+                false
+            }
+        }
+    }
+
+    fn dedup(
+        &self,
+        key: Option<Key>,
+        record: Option<&Record>,
+        send: impl Fn() -> Result<usize>,
+    ) -> Result<usize> {
+        let Some(key) = key else {
+            return send();
+        };
+        let mut state = self.state.lock().unwrap();
+        let forgotten = self.forget(&mut state, false);
+        let duplicate = self.seen(&mut state, key, record);
+        // an LRU may be over by the new key
+        let forgotten = forgotten.and(self.forget(&mut state, false));
+        drop(state);
+        let sent = match duplicate {
+            true => Ok(0),
+            false => send(),
+        };
+        forgotten?;
+        sent
+    }
+}
+
+impl<S: Sender> Sender for Dedup<S> {
+    /// returns 0 for a duplicate
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.dedup(Some((None, buf.to_vec())), None, || self.sender.send(buf))
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        let key = self
+            .key
+            .of(record)
+            .map(|key| (Some(record.pattern_id), key));
+        self.dedup(key, Some(record), || self.sender.send_record(record))
+    }
+
+    /// sends the counts of every key and forgets them
+    fn flush(&self) -> Result<()> {
+        self.forget(&mut self.state.lock().unwrap(), true)?;
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+impl<S: Sender> Drop for Dedup<S> {
+    /// sends the pending counts, the error is lost, flush to see it
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            let _ = self.forget(&mut state, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{Encoded, Format};
+    use crate::matcher::{Captures, Match};
+    use crate::sender::StdoutSink;
+    use std::sync::Arc;
+
+    fn sink() -> Arc<StdoutSink<Vec<u8>>> {
+        Arc::new(StdoutSink::with_writer(Vec::new()).delimiter(Some(b' ')))
+    }
+
+    /// what `dedup` sent, once dropped
+    fn output<S: Sender>(dedup: Dedup<S>, sink: Arc<StdoutSink<Vec<u8>>>) -> String {
+        drop(dedup);
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        String::from_utf8(sink.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_dedup_exact() {
+        let sink = sink();
+        let dedup = Dedup::new(Arc::clone(&sink));
+        for buf in [b"a", b"b", b"a", b"a", b"c", b"b"] {
+            dedup.send(buf).unwrap();
+        }
+        assert_eq!(dedup.dropped(), 3);
+        dedup.flush().unwrap();
+        // counted again once forgotten
+        dedup.send(b"a").unwrap();
+        assert_eq!(output(dedup, sink), "a b c a ");
+    }
+
+    #[test]
+    fn test_dedup_capture_with_count() {
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()));
+        let json = Encoded::new(Arc::clone(&sink), Format::JsonLines);
+        let dedup = Dedup::new(json).key(DedupKey::Capture(1)).count(true);
+        let send = |subject: &[u8], user: (usize, usize)| {
+            let captures = Captures::new(subject, vec![Some((0, subject.len())), Some(user)]);
+            let record = Record::new(0, "-", Match::new(subject, 0, 0)).with_captures(&captures);
+            dedup.send_record(&record).unwrap()
+        };
+        assert_ne!(send(b"denied bob 1", (7, 10)), 0);
+        assert_ne!(send(b"denied eve 2", (7, 10)), 0);
+        assert_eq!(send(b"denied bob 3", (7, 10)), 0);
+        assert_eq!(send(b"denied bob 4", (7, 10)), 0);
+        dedup.flush().unwrap();
+        drop(dedup);
+
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        let out = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[2].contains(r#""match":"denied bob 4""#),
+            "{}",
+            lines[2]
+        );
+        assert!(lines[2].ends_with(r#","count":2}"#), "{}", lines[2]);
+    }
+
+    #[test]
+    fn test_dedup_count_batched() {
+        use crate::sender::{unbatch, Batched};
+
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()).delimiter(None));
+        let batched = Batched::new(Arc::clone(&sink));
+        let dedup = Dedup::new(Encoded::new(batched, Format::JsonLines)).count(true);
+        let m = Match::new(b"a", 0, 1);
+        for _ in 0..3 {
+            dedup.send_record(&Record::new(0, "-", m)).unwrap();
+        }
+        dedup.flush().unwrap();
+        drop(dedup);
+        let batch = Arc::try_unwrap(sink).ok().unwrap().into_inner();
+        let sent = unbatch(&batch).unwrap();
+        assert_eq!(sent.len(), 2);
+        let count = String::from_utf8_lossy(sent[1]);
+        assert!(count.ends_with(r#","count":2}"#), "{}", count);
+
+        // without the encoding, the count is the matched bytes again
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()).delimiter(None));
+        let dedup = Dedup::new(Batched::new(Arc::clone(&sink))).count(true);
+        for _ in 0..3 {
+            dedup.send_record(&Record::new(0, "-", m)).unwrap();
+        }
+        dedup.flush().unwrap();
+        drop(dedup);
+        let batch = Arc::try_unwrap(sink).ok().unwrap().into_inner();
+        assert_eq!(unbatch(&batch).unwrap(), [b"a", b"a"]);
+    }
+
+    #[test]
+    fn test_dedup_lru_and_window() {
+        let lru = sink();
+        let dedup = Dedup::new(Arc::clone(&lru)).memory(Memory::Lru(2));
+        for buf in [b"a", b"b", b"a", b"c", b"a", b"b"] {
+            dedup.send(buf).unwrap();
+        }
+        // `b` was the least recently seen when `c` came
+        assert_eq!(output(dedup, lru), "a b c b ");
+
+        let window = sink();
+        let dedup = Dedup::new(Arc::clone(&window)).memory(Memory::Window(Duration::ZERO));
+        for buf in [b"a", b"a"] {
+            dedup.send(buf).unwrap();
+        }
+        assert_eq!(output(dedup, window), "a a ");
+    }
+
+    #[test]
+    fn test_dedup_count_errors_and_drop() {
+        use anyhow::bail;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        /// fails on counts while down
+        struct Flaky {
+            down: AtomicBool,
+            sink: Arc<StdoutSink<Vec<u8>>>,
+        }
+
+        impl Sender for Flaky {
+            fn send(&self, buf: &[u8]) -> Result<usize> {
+                self.sink.send(buf)
+            }
+
+            fn send_record(&self, record: &Record) -> Result<usize> {
+                if record.count.is_some() && self.down.load(Ordering::Relaxed) {
+                    bail!("down");
+                }
+                self.sink.send(record.as_bytes())
+            }
+        }
+
+        let sink = sink();
+        let flaky = Flaky {
+            down: AtomicBool::new(true),
+            sink: Arc::clone(&sink),
+        };
+        let dedup = Dedup::new(flaky).memory(Memory::Lru(1)).count(true);
+        let m = |bytes: &'static [u8]| Match::new(bytes, 0, bytes.len());
+        for bytes in [b"a", b"a"] {
+            dedup.send_record(&Record::new(0, "-", m(bytes))).unwrap();
+        }
+        // `a` is forgotten, its count fails, `b` goes out all the same
+        assert!(dedup.send_record(&Record::new(0, "-", m(b"b"))).is_err());
+        dedup.send_record(&Record::new(0, "-", m(b"b"))).unwrap();
+        dedup.get_ref().down.store(false, Ordering::Relaxed);
+        // the count of `b` on drop
+        assert_eq!(output(dedup, sink), "a b b ");
+    }
+}
@@ -0,0 +1,236 @@
+use super::{Metrics, Sender, Stats};
+use anyhow::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When written results are synced to disk with `fsync`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// leave it to the OS
+    #[default]
+    Never,
+    /// after every result
+    Always,
+    /// after a result, if the last sync is older than this, and on flush and
+    /// drop for the results since
+    Interval(Duration),
+}
+
+struct Output {
+    file: File,
+    /// bytes in the current file
+    size: u64,
+    last_sync: Instant,
+    /// written since the last sync
+    unsynced: bool,
+}
+
+/// Appends every result to a file followed by a delimiter, `\n` by default,
+/// optionally rotating it by size: `out.log` is renamed to `out.log.1`,
+/// `out.log.1` to `out.log.2` and so on, the oldest beyond `keep` is dropped.
+pub struct FileSink {
+    path: PathBuf,
+    delimiter: Option<u8>,
+    sync: SyncPolicy,
+    /// rotate before a file would grow past this many bytes
+    max_size: Option<u64>,
+    /// rotated files to keep
+    keep: usize,
+    output: Mutex<Output>,
+    metrics: Metrics,
+}
+
+impl FileSink {
+    /// open `path` for appending, created if missing
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let output = FileSink::open(&path)?;
+        Ok(Self {
+            path,
+            delimiter: Some(b'\n'),
+            sync: SyncPolicy::default(),
+            max_size: None,
+            keep: 0,
+            output: Mutex::new(output),
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// `None` to write the results back to back, e.g. when already framed
+    pub fn delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// rotate at `max_size` bytes, keeping `keep` old files
+    pub fn rotate(mut self, max_size: u64, keep: usize) -> Self {
+        self.max_size = Some(max_size);
+        self.keep = keep;
+        self
+    }
+
+    fn open(path: &Path) -> Result<Output> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Output {
+            file,
+            size,
+            last_sync: Instant::now(),
+            unsynced: false,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate_files(&self, output: &mut Output) -> Result<()> {
+        output.file.sync_all()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        *output = FileSink::open(&self.path)?;
+        Ok(())
+    }
+
+    fn sync_data(&self, output: &mut Output) -> Result<()> {
+        output.file.sync_data()?;
+        output.last_sync = Instant::now();
+        output.unsynced = false;
+        Ok(())
+    }
+
+    /// sync what an interval left unsynced
+    fn sync_rest(&self, output: &mut Output) -> Result<()> {
+        match self.sync {
+            SyncPolicy::Interval(_) if output.unsynced => self.sync_data(output),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Sender for FileSink {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| {
+            let mut record = Vec::with_capacity(buf.len() + 1);
+            record.extend_from_slice(buf);
+            record.extend(self.delimiter);
+
+            let mut output = self.output.lock().unwrap();
+            if let Some(max_size) = self.max_size {
+                // a record larger than `max_size` still gets a file of its own
+                if output.size > 0 && output.size + record.len() as u64 > max_size {
+                    self.rotate_files(&mut output)?;
+                }
+            }
+            output.file.write_all(&record)?;
+            output.size += record.len() as u64;
+            output.unsynced = true;
+            let sync = match self.sync {
+                SyncPolicy::Never => false,
+                SyncPolicy::Always => true,
+                SyncPolicy::Interval(interval) => output.last_sync.elapsed() >= interval,
+            };
+            if sync {
+                self.sync_data(&mut output)?;
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sync_rest(&mut self.output.lock().unwrap())
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = self.sync_rest(&mut output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_sink_append() {
+        let path = temp_path("file-sink");
+        let sink = FileSink::new(&path).unwrap().sync(SyncPolicy::Always);
+        assert_eq!(sink.send(b"abc").unwrap(), 3);
+        drop(sink);
+        let sink = FileSink::new(&path).unwrap();
+        sink.send(b"def").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc\ndef\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_sink_sync_interval() {
+        let path = temp_path("file-sink-sync");
+        let sink = FileSink::new(&path)
+            .unwrap()
+            .sync(SyncPolicy::Interval(Duration::from_secs(3600)));
+        sink.send(b"abc").unwrap();
+        // the interval isn't up, so it waits for the flush
+        assert!(sink.output.lock().unwrap().unsynced);
+        sink.flush().unwrap();
+        assert!(!sink.output.lock().unwrap().unsynced);
+        sink.flush().unwrap();
+        drop(sink);
+        assert_eq!(fs::read(&path).unwrap(), b"abc\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_sink_rotate() {
+        let path = temp_path("file-sink-rotate");
+        let sink = FileSink::new(&path).unwrap().rotate(8, 2);
+        for record in [b"aaa", b"bbb", b"ccc", b"ddd", b"eee"] {
+            sink.send(record).unwrap();
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"eee\n");
+        assert_eq!(fs::read(sink.rotated(1)).unwrap(), b"ccc\nddd\n");
+        assert_eq!(fs::read(sink.rotated(2)).unwrap(), b"aaa\nbbb\n");
+        sink.send(b"fff").unwrap();
+        sink.send(b"ggg").unwrap();
+        // the oldest is gone
+        assert_eq!(fs::read(sink.rotated(2)).unwrap(), b"ccc\nddd\n");
+        assert!(!sink.rotated(3).exists());
+        for n in 1..=2 {
+            fs::remove_file(sink.rotated(n)).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
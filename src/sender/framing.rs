@@ -0,0 +1,128 @@
+//! Framing of results on stream transports, where message boundaries are lost.
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+use anyhow::{bail, Result};
+
+/// max payload of a length prefixed frame, so a corrupt prefix can't allocate gigabytes
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Framing {
+    /// a big endian `u32` payload length, then the payload
+    #[default]
+    LengthPrefix,
+    /// the payload, then `\n`, the payload must not contain `\n`
+    Newline,
+    /// the payload length in ASCII decimal, a space, then the payload,
+    /// syslog over TCP (RFC 6587)
+    OctetCounting,
+}
+
+impl Framing {
+    /// append the framed `buf` to `out`
+    pub fn encode(&self, buf: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Framing::LengthPrefix => {
+                if buf.len() > MAX_FRAME {
+                    bail!("frame too large: {} bytes", buf.len());
+                }
+                out.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+                out.extend_from_slice(buf);
+            }
+            Framing::Newline => {
+                if buf.contains(&b'\n') {
+                    bail!("newline in newline delimited frame");
+                }
+                out.extend_from_slice(buf);
+                out.push(b'\n');
+            }
+            Framing::OctetCounting => {
+                if buf.len() > MAX_FRAME {
+                    bail!("frame too large: {} bytes", buf.len());
+                }
+                let _ = write!(out, "{} ", buf.len());
+                out.extend_from_slice(buf);
+            }
+        }
+        Ok(())
+    }
+
+    /// read the next payload, `None` on a clean end of stream
+    pub fn decode<R: BufRead>(&self, reader: &mut R) -> Result<Option<Vec<u8>>> {
+        match self {
+            Framing::LengthPrefix => {
+                let mut len = [0u8; 4];
+                match reader.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => bail!(e),
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_FRAME {
+                    bail!("frame too large: {} bytes", len);
+                }
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf)?;
+                Ok(Some(buf))
+            }
+            Framing::Newline => {
+                let mut buf = Vec::new();
+                if reader.read_until(b'\n', &mut buf)? == 0 {
+                    return Ok(None);
+                }
+                if buf.pop() != Some(b'\n') {
+                    bail!("truncated frame: {} bytes", buf.len());
+                }
+                Ok(Some(buf))
+            }
+            Framing::OctetCounting => {
+                let mut len = Vec::new();
+                // a length of up to 8 digits, so garbage isn't read to the end
+                if reader.by_ref().take(9).read_until(b' ', &mut len)? == 0 {
+                    return Ok(None);
+                }
+                if len.pop() != Some(b' ') || len.is_empty() || len.len() > 8 {
+                    bail!("bad octet count: {:?}", String::from_utf8_lossy(&len));
+                }
+                let len: usize = std::str::from_utf8(&len)?.parse()?;
+                if len > MAX_FRAME {
+                    bail!("frame too large: {} bytes", len);
+                }
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing_round_trip() {
+        for framing in [
+            Framing::LengthPrefix,
+            Framing::Newline,
+            Framing::OctetCounting,
+        ] {
+            let mut out = Vec::new();
+            framing.encode(b"abc", &mut out).unwrap();
+            framing.encode(b"", &mut out).unwrap();
+            let mut reader = &out[..];
+            assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"abc");
+            assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"");
+            assert!(framing.decode(&mut reader).unwrap().is_none());
+        }
+        assert!(Framing::Newline.encode(b"a\nb", &mut Vec::new()).is_err());
+        // truncated payload
+        let mut reader = &[0u8, 0, 0, 5, b'a'][..];
+        assert!(Framing::LengthPrefix.decode(&mut reader).is_err());
+        let mut out = Vec::new();
+        Framing::OctetCounting.encode(b"<13>1 -", &mut out).unwrap();
+        assert_eq!(out, b"7 <13>1 -");
+        let mut reader = &b"x1 a"[..];
+        assert!(Framing::OctetCounting.decode(&mut reader).is_err());
+    }
+}
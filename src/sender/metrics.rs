@@ -0,0 +1,391 @@
+//! What a sender did so far: messages, bytes, errors, retries and the latency
+//! of its sends, see [`Sender::stats`].
+//!
+//! Transports keep the counters in a [`Metrics`], wrappers pass on the stats
+//! of the sender they wrap. [`Exporter`] writes them in the Prometheus text
+//! format, to a file every so often or to whoever asks over HTTP.
+
+use super::Sender;
+use anyhow::Result;
+use std::fmt::{self, Display, Write as _};
+use std::fs;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// upper bounds of the latency buckets in µs, the last bucket is the rest
+const BUCKETS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+];
+
+/// Send latencies, counted per bucket.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS.len() + 1],
+    /// in µs
+    sum: u64,
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum)
+    }
+
+    /// upper bound and the count of latencies up to it, cumulative, `None`
+    /// for the last bucket
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        let bounds = BUCKETS.iter().map(|us| Some(Duration::from_micros(*us)));
+        bounds
+            .chain([None])
+            .zip(self.counts.iter().scan(0, |total, n| {
+                *total += n;
+                Some(*total)
+            }))
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, n) in self.counts.iter_mut().zip(other.counts) {
+            *count += n;
+        }
+        self.sum += other.sum;
+    }
+}
+
+/// A snapshot of the counters of a sender.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// sends that succeeded
+    pub messages: u64,
+    pub bytes: u64,
+    /// sends that failed
+    pub errors: u64,
+    /// packets sent again, or reconnects
+    pub retries: u64,
+    pub latency: Histogram,
+}
+
+impl Stats {
+    /// add the counters of `other`, e.g. of another route
+    pub fn merge(&mut self, other: &Stats) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        self.retries += other.retries;
+        self.latency.merge(&other.latency);
+    }
+
+    /// in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("messages", "Messages sent.", self.messages),
+            ("bytes", "Bytes sent.", self.bytes),
+            ("errors", "Sends that failed.", self.errors),
+            ("retries", "Packets resent and reconnects.", self.retries),
+        ] {
+            let _ = writeln!(out, "# HELP xipin_sender_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE xipin_sender_{}_total counter", name);
+            let _ = writeln!(out, "xipin_sender_{}_total {}", name, value);
+        }
+        let name = "xipin_sender_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of a send.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (le, count) in self.latency.buckets() {
+            let le = le.map_or("+Inf".to_string(), |le| le.as_secs_f64().to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.latency.sum().as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.latency.count());
+        out
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.latency.count().max(1) as u32;
+        write!(
+            f,
+            "messages {} bytes {} errors {} retries {}, {:?} per send",
+            self.messages,
+            self.bytes,
+            self.errors,
+            self.retries,
+            self.latency.sum() / count,
+        )
+    }
+}
+
+/// The counters a transport keeps.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    retries: AtomicU64,
+    latency: [AtomicU64; BUCKETS.len() + 1],
+    latency_sum: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// time `send` and count the bytes it returns, or its error
+    pub fn record(&self, send: impl FnOnce() -> Result<usize>) -> Result<usize> {
+        let start = Instant::now();
+        let result = send();
+        self.count(start, &result);
+        result
+    }
+
+    /// same as [`Metrics::record`], for an async send
+    pub async fn record_async(&self, send: impl Future<Output = Result<usize>>) -> Result<usize> {
+        let start = Instant::now();
+        let result = send.await;
+        self.count(start, &result);
+        result
+    }
+
+    fn count(&self, start: Instant, result: &Result<usize>) {
+        let us = start.elapsed().as_micros() as u64;
+        let bucket = BUCKETS.partition_point(|bound| *bound < us);
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum.fetch_add(us, Ordering::Relaxed);
+        match result {
+            Ok(len) => {
+                self.messages.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(*len as u64, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        Stats {
+            messages: load(&self.messages),
+            bytes: load(&self.bytes),
+            errors: load(&self.errors),
+            retries: load(&self.retries),
+            latency: Histogram {
+                counts: self.latency.each_ref().map(load),
+                sum: load(&self.latency_sum),
+            },
+        }
+    }
+}
+
+/// Serves the stats of a sender in the Prometheus text format until dropped.
+///
+/// A write or request that fails is counted in [`Exporter::errors`], there is
+/// nobody else to tell.
+pub struct Exporter {
+    stop: Arc<AtomicBool>,
+    errors: Arc<AtomicU64>,
+    /// of the HTTP endpoint
+    addr: Option<SocketAddr>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// write the stats to `path` every `interval`, and once more when dropped
+    pub fn file<S>(sender: Arc<S>, path: impl Into<PathBuf>, interval: Duration) -> Self
+    where
+        S: Sender + Send + Sync + 'static,
+    {
+        let path = path.into();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let errors = Arc::new(AtomicU64::new(0));
+        let failed = Arc::clone(&errors);
+        let handle = thread::spawn(move || loop {
+            let stopping = stopped.load(Ordering::Acquire);
+            // renamed into place, so a scraper never reads half a file
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(".tmp");
+            if fs::write(&tmp, sender.stats().to_prometheus())
+                .and_then(|()| fs::rename(&tmp, &path))
+                .is_err()
+            {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            if stopping {
+                return;
+            }
+            thread::park_timeout(interval);
+        });
+        Self {
+            stop,
+            errors,
+            addr: None,
+            handle: Some(handle),
+        }
+    }
+
+    /// answer every HTTP request on `addr` with the stats, e.g.
+    /// `127.0.0.1:9100`, each connection on a thread of its own so a slow
+    /// client holds up neither the others nor the drop
+    pub fn http<S>(sender: Arc<S>, addr: &str) -> Result<Self>
+    where
+        S: Sender + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let errors = Arc::new(AtomicU64::new(0));
+        let failed = Arc::clone(&errors);
+        let handle = thread::spawn(move || {
+            for conn in listener.incoming() {
+                if stopped.load(Ordering::Acquire) {
+                    return;
+                }
+                let stats = sender.stats();
+                let failed = Arc::clone(&failed);
+                thread::spawn(move || {
+                    if conn.and_then(|conn| respond(conn, &stats)).is_err() {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            stop,
+            errors,
+            addr: Some(addr),
+            handle: Some(handle),
+        })
+    }
+
+    /// where the HTTP endpoint listens
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// file writes or HTTP requests that failed so far
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+/// whatever the request, it's for the metrics
+fn respond(conn: TcpStream, stats: &Stats) -> std::io::Result<()> {
+    conn.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&conn);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let body = stats.to_prometheus();
+    write!(
+        &conn,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        match self.addr {
+            // wake up the accept
+            Some(addr) => drop(TcpStream::connect(addr)),
+            None => handle.thread().unpark(),
+        }
+        let _ = handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::StdoutSink;
+    use anyhow::bail;
+    use std::io::Read;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.record(|| Ok(4)).unwrap();
+        metrics.record(|| Ok(6)).unwrap();
+        assert!(metrics.record(|| bail!("down")).is_err());
+        metrics.retry();
+
+        let stats = metrics.stats();
+        assert_eq!(
+            (stats.messages, stats.bytes, stats.errors, stats.retries),
+            (2, 10, 1, 1)
+        );
+        assert_eq!(stats.latency.count(), 3);
+        let buckets: Vec<_> = stats.latency.buckets().collect();
+        assert_eq!(buckets.len(), BUCKETS.len() + 1);
+        assert_eq!(buckets.last(), Some(&(None, 3)));
+
+        let text = stats.to_prometheus();
+        assert!(text.contains("xipin_sender_bytes_total 10\n"), "{}", text);
+        assert!(text.contains("xipin_sender_latency_seconds_bucket{le=\"0.00005\"} "));
+        assert!(text.contains("xipin_sender_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("xipin_sender_latency_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_exporter() {
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()));
+        sink.send(b"abc").unwrap();
+
+        let path = std::env::temp_dir().join(format!("xipin-metrics-{}.prom", std::process::id()));
+        let exporter = Exporter::file(Arc::clone(&sink), &path, Duration::from_secs(3600));
+        sink.send(b"def").unwrap();
+        // the last write is on drop
+        drop(exporter);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(text.contains("xipin_sender_messages_total 2\n"), "{}", text);
+
+        let exporter = Exporter::http(Arc::clone(&sink), "127.0.0.1:0").unwrap();
+        // an idle client doesn't hold up the others
+        let _idle = TcpStream::connect(exporter.local_addr().unwrap()).unwrap();
+        let mut conn = TcpStream::connect(exporter.local_addr().unwrap()).unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(
+            response.contains("xipin_sender_bytes_total 6\n"),
+            "{}",
+            response
+        );
+        let start = Instant::now();
+        drop(exporter);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // counted, not printed
+        let missing = std::env::temp_dir().join(format!("xipin-missing-{}", std::process::id()));
+        let exporter = Exporter::file(sink, missing.join("m.prom"), Duration::from_secs(3600));
+        while exporter.errors() == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(exporter.errors(), 1);
+    }
+}
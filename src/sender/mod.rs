@@ -1,6 +1,118 @@
+#[cfg(feature = "async")]
+mod asynchronous;
+mod batch;
+mod compress;
+mod conn;
+mod dedup;
+mod file;
+mod framing;
+mod metrics;
+mod pipeline;
+mod rate;
+mod reliable;
+mod router;
+mod sign;
+mod stdout;
+mod syslog;
+mod tcp;
 mod udp;
+#[cfg(unix)]
+mod unix;
+use crate::encoder::Record;
 use anyhow::Result;
+#[cfg(feature = "async")]
+pub use asynchronous::*;
+pub use batch::*;
+pub use compress::*;
+pub use dedup::*;
+pub use file::*;
+pub use framing::*;
+pub use metrics::*;
+pub use pipeline::*;
+pub use rate::*;
+pub use reliable::*;
+pub use router::*;
+pub use sign::*;
+use std::sync::Arc;
+pub use stdout::*;
+pub use syslog::*;
+pub use tcp::*;
 pub use udp::*;
+#[cfg(unix)]
+pub use unix::*;
 pub trait Sender {
     fn send(&self, buf: &[u8]) -> Result<usize>;
+
+    /// send a structured match, the matched bytes unless encoded,
+    /// see [`crate::encoder::Encoded`]. Wrappers that rework payloads,
+    /// [`Batched`], [`Compressed`] and [`Signed`], take those bytes as any
+    /// other payload, so the `Encoded` layer goes above them
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        self.send(record.as_bytes())
+    }
+
+    /// send whatever is buffered, e.g. on shutdown
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// the counters of the transport, a wrapper passes on those of the
+    /// sender it wraps
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+}
+
+impl<S: Sender + ?Sized> Sender for &S {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        (**self).send_record(record)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
+}
+
+impl<S: Sender + ?Sized> Sender for Box<S> {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        (**self).send_record(record)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
+}
+
+impl<S: Sender + ?Sized> Sender for Arc<S> {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        (**self).send_record(record)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
 }
@@ -0,0 +1,643 @@
+//! A bounded queue between the matcher and a sender, with a thread of its own
+//! to send, so a slow or blocked sink doesn't stall matching.
+//!
+//! What happens when the queue is full is up to the [`Overflow`] policy. Spilled
+//! results go to a file and are sent, in order, once the queue has drained.
+
+use super::{Sender, Stats};
+use crate::encoder::{OwnedRecord, Record};
+use anyhow::{bail, Error, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Overflow {
+    /// wait for room, matching slows down to the sender
+    #[default]
+    Block,
+    /// drop the result that doesn't fit
+    DropNewest,
+    /// drop the oldest queued result to make room
+    DropOldest,
+    /// append to this file until the queue drains, it is removed on drop
+    Spill(PathBuf),
+}
+
+/// counts since the pipeline started
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PipelineStats {
+    /// waiting in the queue or the spill file
+    pub queued: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    /// results that went through the spill file
+    pub spilled: u64,
+    /// failed sends, the results are lost
+    pub errors: u64,
+}
+
+enum Item {
+    Bytes(Vec<u8>),
+    Record(OwnedRecord),
+}
+
+struct Queue {
+    items: VecDeque<Item>,
+    spill: Option<Spill>,
+    /// the sender thread has an item out of the queue
+    busy: bool,
+    closed: bool,
+    /// the sender thread is gone, drained after closing or panicked
+    stopped: bool,
+    /// the first send error since the last flush
+    error: Option<Error>,
+    stats: PipelineStats,
+}
+
+impl Queue {
+    fn is_idle(&self) -> bool {
+        self.items.is_empty() && self.spill.as_ref().is_none_or(Spill::is_empty) && !self.busy
+    }
+}
+
+struct Shared<S> {
+    sender: S,
+    capacity: usize,
+    overflow: Overflow,
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    idle: Condvar,
+}
+
+/// A sender that queues results for a thread sending them to `S`.
+///
+/// [`Sender::send`] returns the length queued, 0 if the result was dropped.
+/// Errors of `S` are counted and returned by the next [`Sender::flush`].
+/// Dropping it sends what's queued first.
+pub struct Pipeline<S: Sender + Send + Sync + 'static> {
+    shared: Arc<Shared<S>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<S: Sender + Send + Sync + 'static> Pipeline<S> {
+    /// queue up to `capacity` results, at least 1
+    pub fn new(sender: S, capacity: usize, overflow: Overflow) -> Result<Self> {
+        let spill = match &overflow {
+            Overflow::Spill(path) => Some(Spill::create(path.clone())?),
+            _ => None,
+        };
+        let shared = Arc::new(Shared {
+            sender,
+            capacity: capacity.max(1),
+            overflow,
+            queue: Mutex::new(Queue {
+                items: VecDeque::with_capacity(capacity.max(1)),
+                spill,
+                busy: false,
+                closed: false,
+                stopped: false,
+                error: None,
+                stats: PipelineStats::default(),
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            idle: Condvar::new(),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("pipeline".to_string())
+                .spawn(move || {
+                    let run = panic::catch_unwind(AssertUnwindSafe(|| shared.run()));
+                    shared.stop();
+                    if let Err(panic) = run {
+                        panic::resume_unwind(panic);
+                    }
+                })?
+        };
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.shared.sender
+    }
+
+    pub fn queue_stats(&self) -> PipelineStats {
+        let queue = self.shared.queue.lock().unwrap();
+        let spilled = queue.spill.as_ref().map_or(0, |s| s.count);
+        PipelineStats {
+            queued: queue.items.len() + spilled,
+            ..queue.stats
+        }
+    }
+
+    fn push(&self, item: Item, len: usize) -> Result<usize> {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.stopped {
+            bail!("pipeline sender thread has stopped");
+        }
+        // once spilling, everything goes to the file to keep the order
+        let spilling = queue.spill.as_ref().is_some_and(|s| !s.is_empty());
+        if spilling || queue.items.len() >= shared.capacity {
+            match &shared.overflow {
+                Overflow::Block => {
+                    queue = shared
+                        .not_full
+                        .wait_while(queue, |q| q.items.len() >= shared.capacity && !q.stopped)
+                        .unwrap();
+                    if queue.stopped {
+                        bail!("pipeline sender thread has stopped");
+                    }
+                }
+                Overflow::DropNewest => {
+                    queue.stats.dropped += 1;
+                    return Ok(0);
+                }
+                Overflow::DropOldest => {
+                    queue.items.pop_front();
+                    queue.stats.dropped += 1;
+                }
+                Overflow::Spill(_) => {
+                    let Some(spill) = queue.spill.as_mut() else {
+                        return Ok(len);
+                    };
+                    // written without the queue locked, so neither the
+                    // sender thread nor other sends wait on the disk
+                    let writer = Arc::clone(&spill.writer);
+                    spill.writing += 1;
+                    drop(queue);
+                    let written = append(&writer, &encode(&item));
+                    let mut queue = shared.queue.lock().unwrap();
+                    let queue = &mut *queue;
+                    if let Some(spill) = queue.spill.as_mut() {
+                        spill.writing -= 1;
+                        if written.is_ok() {
+                            spill.count += 1;
+                            queue.stats.spilled += 1;
+                        }
+                    }
+                    shared.not_empty.notify_one();
+                    return written.map(|_| len);
+                }
+            }
+        }
+        queue.items.push_back(item);
+        shared.not_empty.notify_one();
+        Ok(len)
+    }
+}
+
+impl<S: Sender> Shared<S> {
+    /// the next item to send, `None` once closed and drained
+    fn next(&self) -> Option<Item> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.busy = false;
+        loop {
+            if let Some(item) = queue.items.pop_front() {
+                queue.busy = true;
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if let Some(item) = self.unspill(&mut queue) {
+                queue.busy = true;
+                return Some(item);
+            }
+            if queue.closed {
+                self.idle.notify_all();
+                return None;
+            }
+            self.idle.notify_all();
+            queue = self.not_empty.wait(queue).unwrap();
+        }
+    }
+
+    /// the thread is gone, wake whoever waits on it
+    fn stop(&self) {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.stopped = true;
+        self.not_full.notify_all();
+        self.idle.notify_all();
+    }
+
+    fn unspill(&self, queue: &mut MutexGuard<Queue>) -> Option<Item> {
+        let queue = &mut **queue;
+        let spill = queue.spill.as_mut().filter(|s| s.count > 0)?;
+        match spill.pop() {
+            Ok(item) => Some(item),
+            // can't tell where the next one starts, give up on the rest,
+            // and on the ones being written if the file can't be cleared yet
+            Err(e) => {
+                queue.stats.dropped += spill.count as u64;
+                queue.error.get_or_insert(e);
+                spill.count = 0;
+                if spill.writing == 0 {
+                    let _ = spill.clear();
+                }
+                None
+            }
+        }
+    }
+
+    fn run(&self) {
+        while let Some(item) = self.next() {
+            let sent = match &item {
+                Item::Bytes(buf) => self.sender.send(buf),
+                Item::Record(record) => self.sender.send_record(&record.as_record()),
+            };
+            let mut queue = self.queue.lock().unwrap();
+            match sent {
+                Ok(_) => queue.stats.sent += 1,
+                Err(e) => {
+                    queue.stats.errors += 1;
+                    queue.error.get_or_insert(e);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Sender + Send + Sync + 'static> Sender for Pipeline<S> {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.push(Item::Bytes(buf.to_vec()), buf.len())
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        self.push(
+            Item::Record(OwnedRecord::from(record)),
+            record.as_bytes().len(),
+        )
+    }
+
+    /// wait until the queue is sent, then flush `S`
+    fn flush(&self) -> Result<()> {
+        let shared = &self.shared;
+        let mut queue = shared
+            .idle
+            .wait_while(shared.queue.lock().unwrap(), |q| !q.is_idle() && !q.stopped)
+            .unwrap();
+        if let Some(e) = queue.error.take() {
+            return Err(e);
+        }
+        if queue.stopped {
+            bail!("pipeline sender thread has stopped");
+        }
+        drop(queue);
+        shared.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.shared.sender.stats()
+    }
+}
+
+impl<S: Sender + Send + Sync + 'static> Drop for Pipeline<S> {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.not_empty.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = self.shared.sender.flush();
+        if let Some(spill) = self.shared.queue.lock().unwrap().spill.take() {
+            let _ = fs::remove_file(&spill.path);
+        }
+    }
+}
+
+/// The overflow file, entries are appended and read back in order.
+struct Spill {
+    path: PathBuf,
+    /// appended to with the queue unlocked, see [`append`]
+    writer: Arc<Mutex<File>>,
+    reader: BufReader<File>,
+    /// entries written and not read yet
+    count: usize,
+    /// entries being written, not counted yet
+    writing: usize,
+}
+
+const BYTES: u8 = 0;
+const RECORD: u8 = 1;
+
+fn put_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_match((bytes, start, end): &(Vec<u8>, usize, usize), out: &mut Vec<u8>) {
+    out.extend_from_slice(&(*start as u64).to_be_bytes());
+    out.extend_from_slice(&(*end as u64).to_be_bytes());
+    put_bytes(bytes, out);
+}
+
+fn get_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn get_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = get_u64(reader)? as usize;
+    if len > super::MAX_FRAME {
+        bail!("corrupt spill file, entry of {} bytes", len);
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn get_match<R: Read>(reader: &mut R) -> Result<(Vec<u8>, usize, usize)> {
+    let start = get_u64(reader)? as usize;
+    let end = get_u64(reader)? as usize;
+    Ok((get_bytes(reader)?, start, end))
+}
+
+/// an entry of the spill file
+fn encode(item: &Item) -> Vec<u8> {
+    let mut out = Vec::new();
+    match item {
+        Item::Bytes(buf) => {
+            out.push(BYTES);
+            put_bytes(buf, &mut out);
+        }
+        Item::Record(record) => {
+            out.push(RECORD);
+            out.extend_from_slice(&(record.pattern_id as u64).to_be_bytes());
+            let nanos = record
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            out.extend_from_slice(&nanos.to_be_bytes());
+            put_bytes(record.source.as_bytes(), &mut out);
+            put_match(&record.m, &mut out);
+            out.extend_from_slice(&(record.captures.len() as u64).to_be_bytes());
+            for capture in &record.captures {
+                match capture {
+                    Some(m) => {
+                        out.push(1);
+                        put_match(m, &mut out);
+                    }
+                    None => out.push(0),
+                }
+            }
+            match record.count {
+                Some(count) => {
+                    out.push(1);
+                    out.extend_from_slice(&count.to_be_bytes());
+                }
+                None => out.push(0),
+            }
+        }
+    }
+    out
+}
+
+/// add an entry to the end of the spill file
+fn append(writer: &Mutex<File>, entry: &[u8]) -> Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writer.seek(SeekFrom::End(0))?;
+    writer.write_all(entry)?;
+    Ok(())
+}
+
+impl Spill {
+    fn create(path: PathBuf) -> Result<Self> {
+        let writer = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(Self {
+            path,
+            writer: Arc::new(Mutex::new(writer)),
+            reader,
+            count: 0,
+            writing: 0,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.writing == 0
+    }
+
+    fn pop(&mut self) -> Result<Item> {
+        let reader = &mut self.reader;
+        let mut kind = [0u8; 1];
+        reader.read_exact(&mut kind)?;
+        let item = match kind[0] {
+            BYTES => Item::Bytes(get_bytes(reader)?),
+            RECORD => {
+                let pattern_id = get_u64(reader)? as usize;
+                let timestamp = UNIX_EPOCH + Duration::from_nanos(get_u64(reader)?);
+                let source = String::from_utf8(get_bytes(reader)?)?;
+                let m = get_match(reader)?;
+                let groups = get_u64(reader)?;
+                let mut captures = Vec::new();
+                for _ in 0..groups {
+                    reader.read_exact(&mut kind)?;
+                    captures.push(match kind[0] {
+                        0 => None,
+                        _ => Some(get_match(reader)?),
+                    });
+                }
+                reader.read_exact(&mut kind)?;
+                let count = match kind[0] {
+                    0 => None,
+                    _ => Some(get_u64(reader)?),
+                };
+                Item::Record(OwnedRecord {
+                    pattern_id,
+                    source,
+                    m,
+                    captures,
+                    timestamp,
+                    count,
+                })
+            }
+            kind => bail!("corrupt spill file, entry kind {}", kind),
+        };
+        self.count -= 1;
+        // not while an entry is going in after this one
+        if self.is_empty() {
+            self.clear()?;
+        }
+        Ok(item)
+    }
+
+    /// start over with an empty file
+    fn clear(&mut self) -> Result<()> {
+        self.count = 0;
+        self.writer.lock().unwrap().set_len(0)?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{Captures, Match};
+    use crate::sender::StdoutSink;
+
+    /// a sender that waits for the test to let it go
+    struct Gate {
+        open: Mutex<bool>,
+        opened: Condvar,
+        sink: StdoutSink<Vec<u8>>,
+    }
+
+    impl Gate {
+        fn new() -> Arc<Self> {
+            Arc::new(Gate {
+                open: Mutex::new(false),
+                opened: Condvar::new(),
+                sink: StdoutSink::with_writer(Vec::new()).delimiter(Some(b' ')),
+            })
+        }
+
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    impl Sender for Gate {
+        fn send(&self, buf: &[u8]) -> Result<usize> {
+            let _open = self
+                .opened
+                .wait_while(self.open.lock().unwrap(), |open| !*open);
+            self.sink.send(buf)
+        }
+    }
+
+    fn output(gate: Arc<Gate>) -> String {
+        let gate = Arc::try_unwrap(gate).ok().unwrap();
+        String::from_utf8(gate.sink.into_inner()).unwrap()
+    }
+
+    /// send 1 to 5 through a queue of 2, while the sender is stuck on 1
+    fn overflow(policy: Overflow) -> (String, PipelineStats) {
+        let gate = Gate::new();
+        let pipeline = Pipeline::new(Arc::clone(&gate), 2, policy).unwrap();
+        pipeline.send(b"1").unwrap();
+        while pipeline.queue_stats().queued > 0 {
+            thread::yield_now();
+        }
+        for buf in [b"2", b"3", b"4", b"5"] {
+            pipeline.send(buf).unwrap();
+        }
+        gate.open();
+        pipeline.flush().unwrap();
+        let stats = pipeline.queue_stats();
+        drop(pipeline);
+        (output(gate), stats)
+    }
+
+    #[test]
+    fn test_pipeline_drop_newest() {
+        let (out, stats) = overflow(Overflow::DropNewest);
+        assert_eq!(out, "1 2 3 ");
+        assert_eq!((stats.sent, stats.dropped), (3, 2));
+    }
+
+    #[test]
+    fn test_pipeline_drop_oldest() {
+        let (out, stats) = overflow(Overflow::DropOldest);
+        assert_eq!(out, "1 4 5 ");
+        assert_eq!((stats.sent, stats.dropped), (3, 2));
+    }
+
+    #[test]
+    fn test_pipeline_spill() {
+        let path = std::env::temp_dir().join(format!("spill-{}", std::process::id()));
+        let (out, stats) = overflow(Overflow::Spill(path.clone()));
+        assert_eq!(out, "1 2 3 4 5 ");
+        assert_eq!((stats.sent, stats.spilled, stats.dropped), (5, 2, 0));
+        assert!(!path.exists());
+    }
+
+    /// a sender that panics once the gate opens
+    struct Panics(Arc<Gate>);
+
+    impl Sender for Panics {
+        fn send(&self, _: &[u8]) -> Result<usize> {
+            let _open = self
+                .0
+                .opened
+                .wait_while(self.0.open.lock().unwrap(), |open| !*open);
+            panic!("sender panicked");
+        }
+    }
+
+    #[test]
+    fn test_pipeline_block_sender_panicked() {
+        let gate = Gate::new();
+        let pipeline = Pipeline::new(Panics(Arc::clone(&gate)), 1, Overflow::Block).unwrap();
+        pipeline.send(b"1").unwrap();
+        while pipeline.queue_stats().queued > 0 {
+            thread::yield_now();
+        }
+        pipeline.send(b"2").unwrap();
+        let opener = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            gate.open();
+        });
+        // waits for room that never comes
+        assert!(pipeline.send(b"3").is_err());
+        assert!(pipeline.flush().is_err());
+        opener.join().unwrap();
+    }
+
+    #[test]
+    fn test_pipeline_block_records() {
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()));
+        let pipeline = Pipeline::new(Arc::clone(&sink), 1, Overflow::Block).unwrap();
+        let subject = b"at 2023-04";
+        for _ in 0..10 {
+            let captures = Captures::new(subject, vec![Some((3, 7)), Some((3, 5))]);
+            let record = Record::new(0, "-", Match::new(subject, 0, 0)).with_captures(&captures);
+            pipeline.send_record(&record).unwrap();
+        }
+        pipeline.flush().unwrap();
+        assert_eq!(pipeline.queue_stats().sent, 10);
+        drop(pipeline);
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        assert_eq!(sink.into_inner(), "2023\n".repeat(10).as_bytes());
+    }
+
+    #[test]
+    fn test_spill_round_trip() {
+        let path = std::env::temp_dir().join(format!("spill-rt-{}", std::process::id()));
+        let mut spill = Spill::create(path.clone()).unwrap();
+        let subject = b"at 2023-04";
+        let captures = Captures::new(subject, vec![Some((3, 7)), None]);
+        let record = Record::new(2, "app.log", Match::new(subject, 0, 0))
+            .with_captures(&captures)
+            .with_count(4);
+        let owned = OwnedRecord::from(&record);
+        for item in [Item::Bytes(b"abc".to_vec()), Item::Record(owned.clone())] {
+            append(&spill.writer, &encode(&item)).unwrap();
+            spill.count += 1;
+        }
+        assert!(matches!(spill.pop().unwrap(), Item::Bytes(b) if b == b"abc"));
+        match spill.pop().unwrap() {
+            Item::Record(r) => assert_eq!(r, owned),
+            Item::Bytes(_) => panic!("expected a record"),
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
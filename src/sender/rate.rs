@@ -0,0 +1,333 @@
+//! Token bucket rate limiting, so a noisy pattern can't flood the collector.
+//!
+//! Results over the limit are counted instead of sent, and a summary,
+//! `N results suppressed`, goes out in their place. It's sent with the first
+//! result through once the summary interval is over, or on flush, as a record
+//! if the results come as records, as bytes otherwise.
+
+use super::{Sender, Stats};
+use crate::encoder::Record;
+use crate::matcher::Match;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// the pattern id of summary records
+pub const SUMMARY_PATTERN: usize = usize::MAX;
+
+/// the source of summary records
+pub const SUMMARY_SOURCE: &str = "rate-limit";
+
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Bucket {
+    /// tokens per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// full to start with
+    fn new(rate: f64, burst: f64) -> Self {
+        Bucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn has(&mut self, n: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        self.tokens >= n
+    }
+}
+
+#[derive(Default)]
+struct State {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    patterns: HashMap<usize, Bucket>,
+    /// results suppressed since the last summary
+    suppressed: u64,
+    /// the last result came through [`Sender::send_record`]
+    records: bool,
+    last_summary: Option<Instant>,
+}
+
+/// A sender that passes results to `S` as long as their token buckets allow.
+///
+/// There is no timer: a due summary goes out with the next result let
+/// through, or on [`Sender::flush`]. If results may stop coming for good,
+/// flush every so often so the last suppressions get reported.
+pub struct RateLimited<S: Sender> {
+    sender: S,
+    summary_interval: Duration,
+    state: Mutex<State>,
+}
+
+impl<S: Sender> RateLimited<S> {
+    /// no limits until some are set
+    pub fn new(sender: S) -> Self {
+        Self {
+            sender,
+            summary_interval: SUMMARY_INTERVAL,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// at most `rate` results a second, and `burst` at once
+    pub fn messages(mut self, rate: f64, burst: u32) -> Self {
+        self.state.get_mut().unwrap().messages = Some(Bucket::new(rate, burst as f64));
+        self
+    }
+
+    /// at most `rate` bytes a second, and `burst` at once, a result larger
+    /// than `burst` never goes through
+    pub fn bytes(mut self, rate: f64, burst: usize) -> Self {
+        self.state.get_mut().unwrap().bytes = Some(Bucket::new(rate, burst as f64));
+        self
+    }
+
+    /// a results limit of its own for the pattern `id`, on top of the others
+    pub fn pattern(mut self, id: usize, rate: f64, burst: u32) -> Self {
+        let state = self.state.get_mut().unwrap();
+        state.patterns.insert(id, Bucket::new(rate, burst as f64));
+        self
+    }
+
+    /// how often at most to send a summary, 10s by default
+    pub fn summary_interval(mut self, interval: Duration) -> Self {
+        self.summary_interval = interval;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    /// results suppressed and not reported in a summary yet
+    pub fn suppressed(&self) -> u64 {
+        self.state.lock().unwrap().suppressed
+    }
+
+    /// take the tokens of a result, if every bucket has them
+    fn admit(&self, state: &mut State, len: usize, pattern: Option<usize>) -> bool {
+        let now = Instant::now();
+        let mut buckets = [
+            state.messages.as_mut().map(|b| (b, 1.0)),
+            state.bytes.as_mut().map(|b| (b, len as f64)),
+            pattern
+                .and_then(|id| state.patterns.get_mut(&id))
+                .map(|b| (b, 1.0)),
+        ];
+        if !buckets
+            .iter_mut()
+            .flatten()
+            .all(|(bucket, n)| bucket.has(*n, now))
+        {
+            state.suppressed += 1;
+            return false;
+        }
+        for (bucket, n) in buckets.iter_mut().flatten() {
+            bucket.tokens -= *n;
+        }
+        true
+    }
+
+    /// send the summary if there is one, when the interval is over or `now`,
+    /// the way the last result came
+    fn summary(&self, state: &mut State, now: bool) -> Result<()> {
+        let due = state
+            .last_summary
+            .is_none_or(|last| last.elapsed() >= self.summary_interval);
+        if state.suppressed == 0 || !(now || due) {
+            return Ok(());
+        }
+        let text = format!("{} results suppressed", state.suppressed);
+        if state.records {
+            let m = Match::new(text.as_bytes(), 0, text.len());
+            let record = Record {
+                pattern_id: SUMMARY_PATTERN,
+                source: SUMMARY_SOURCE,
+                m,
+                captures: Vec::new(),
+                timestamp: SystemTime::now(),
+                count: None,
+            };
+            self.sender.send_record(&record)?;
+        } else {
+            self.sender.send(text.as_bytes())?;
+        }
+        // counted again from the next summary on, unless this one fails
+        state.suppressed = 0;
+        state.last_summary = Some(Instant::now());
+        Ok(())
+    }
+
+    fn limit(
+        &self,
+        len: usize,
+        pattern: Option<usize>,
+        send: impl Fn() -> Result<usize>,
+    ) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.records = pattern.is_some();
+        if !self.admit(&mut state, len, pattern) {
+            return Ok(0);
+        }
+        // a failed summary stays pending for the next result or flush, the
+        // result is through the limits whatever became of it
+        let _ = self.summary(&mut state, false);
+        drop(state);
+        send()
+    }
+}
+
+impl<S: Sender> Sender for RateLimited<S> {
+    /// returns 0 for a suppressed result
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.limit(buf.len(), None, || self.sender.send(buf))
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        self.limit(record.as_bytes().len(), Some(record.pattern_id), || {
+            self.sender.send_record(record)
+        })
+    }
+
+    /// sends the pending summary, whatever the interval
+    fn flush(&self) -> Result<()> {
+        self.summary(&mut self.state.lock().unwrap(), true)?;
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::StdoutSink;
+    use std::thread;
+
+    fn sink() -> StdoutSink<Vec<u8>> {
+        StdoutSink::with_writer(Vec::new()).delimiter(Some(b' '))
+    }
+
+    fn output(limited: RateLimited<StdoutSink<Vec<u8>>>) -> String {
+        String::from_utf8(limited.sender.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_rate_limited_messages() {
+        let limited = RateLimited::new(sink())
+            .messages(0.001, 3)
+            .summary_interval(Duration::from_secs(3600));
+        for buf in [b"1", b"2", b"3", b"4", b"5"] {
+            limited.send(buf).unwrap();
+        }
+        assert_eq!(limited.suppressed(), 2);
+        limited.flush().unwrap();
+        assert_eq!(limited.suppressed(), 0);
+        assert_eq!(output(limited), "1 2 3 2 results suppressed ");
+    }
+
+    #[test]
+    fn test_rate_limited_bytes_and_refill() {
+        let limited = RateLimited::new(sink())
+            .bytes(1000.0, 8)
+            .summary_interval(Duration::ZERO);
+        limited.send(b"123456").unwrap();
+        assert_eq!(limited.send(b"123456").unwrap(), 0);
+        thread::sleep(Duration::from_millis(20));
+        // the summary goes first
+        limited.send(b"abcdef").unwrap();
+        assert_eq!(output(limited), "123456 1 results suppressed abcdef ");
+    }
+
+    #[test]
+    fn test_rate_limited_per_pattern() {
+        let limited = RateLimited::new(sink()).pattern(1, 0.001, 1);
+        let m = Match::new(b"x", 0, 1);
+        for id in [1, 0, 1, 0] {
+            limited.send_record(&Record::new(id, "-", m)).unwrap();
+        }
+        // the first summary is due right away
+        assert_eq!(limited.suppressed(), 0);
+        assert_eq!(output(limited), "x x 1 results suppressed x ");
+    }
+
+    #[test]
+    fn test_rate_limited_summary_fails() {
+        use anyhow::bail;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        /// fails on summaries until told otherwise
+        #[derive(Default)]
+        struct Flaky {
+            up: AtomicBool,
+            sent: Mutex<Vec<String>>,
+        }
+
+        impl Sender for Flaky {
+            fn send(&self, buf: &[u8]) -> Result<usize> {
+                let buf = String::from_utf8_lossy(buf).into_owned();
+                self.sent.lock().unwrap().push(buf);
+                Ok(1)
+            }
+
+            fn send_record(&self, record: &Record) -> Result<usize> {
+                if record.pattern_id == SUMMARY_PATTERN && !self.up.load(Ordering::Relaxed) {
+                    bail!("down");
+                }
+                self.send(record.as_bytes())
+            }
+        }
+
+        let limited = RateLimited::new(Flaky::default())
+            .messages(1000.0, 1)
+            .summary_interval(Duration::ZERO);
+        let m = Match::new(b"x", 0, 1);
+        limited.send_record(&Record::new(0, "-", m)).unwrap();
+        limited.send_record(&Record::new(0, "-", m)).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        // the summary fails, the result still goes out
+        limited.send_record(&Record::new(0, "-", m)).unwrap();
+        assert_eq!(limited.suppressed(), 1);
+        assert!(limited.flush().is_err());
+        limited.get_ref().up.store(true, Ordering::Relaxed);
+        limited.flush().unwrap();
+        assert_eq!(limited.suppressed(), 0);
+        let sent = limited.get_ref().sent.lock().unwrap().clone();
+        assert_eq!(sent, ["x", "x", "1 results suppressed"]);
+    }
+
+    #[test]
+    fn test_rate_limited_batched() {
+        use crate::sender::{unbatch, Batched};
+        use std::sync::Arc;
+
+        let sink = Arc::new(StdoutSink::with_writer(Vec::new()).delimiter(None));
+        let limited = RateLimited::new(Batched::new(Arc::clone(&sink)))
+            .messages(0.001, 1)
+            .summary_interval(Duration::ZERO);
+        for buf in [b"1", b"2", b"3"] {
+            limited.send(buf).unwrap();
+        }
+        assert_eq!(limited.suppressed(), 2);
+        limited.flush().unwrap();
+        assert_eq!(limited.suppressed(), 0);
+        drop(limited);
+        let batch = Arc::try_unwrap(sink).ok().unwrap().into_inner();
+        let sent = unbatch(&batch).unwrap();
+        assert_eq!(sent, [&b"1"[..], b"2 results suppressed"]);
+    }
+}
@@ -0,0 +1,584 @@
+//! Reliable delivery over [`Udp`]: sequence numbers, acks and retransmission.
+//!
+//! Every payload goes out as one datagram behind a [`RELIABLE_HEADER`]: the
+//! kind, a session id picked at start, the sequence number and the oldest
+//! sequence number still unacked. [`ReliableReceiver`] acks the next sequence
+//! number it expects, so one ack covers everything before it. Packets not acked
+//! within the timeout are sent again, the receiver drops duplicates and holds
+//! back early packets, so every payload is delivered exactly once and in order.
+//! A packet still not acked after `max_retries` resends is given up on: the
+//! send or flush that finds it returns an error, and the receiver skips it
+//! once the base of later packets has moved past it.
+//!
+//! At most `window` packets are in flight, a send blocks until an ack makes
+//! room. There is no background thread, acks are read and packets resent while
+//! sending or flushing, so call [`Sender::flush`] before exiting.
+
+use super::{Metrics, Sender, Stats, Udp};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+
+/// kind `u8`, session `u32`, sequence number `u64` and base `u64`, big endian
+pub const RELIABLE_HEADER: usize = 21;
+
+/// max payload of a single datagram
+const MAX_DATAGRAM: usize = 65507;
+
+const WINDOW: usize = 64;
+const TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RETRIES: u32 = 10;
+
+/// early packets a receiver holds per peer, the rest are dropped and resent
+const MAX_EARLY: u64 = 1024;
+
+struct Header {
+    kind: u8,
+    session: u32,
+    seq: u64,
+    /// the oldest packet the sender still waits an ack for
+    base: u64,
+}
+
+impl Header {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.kind);
+        out.extend_from_slice(&self.session.to_be_bytes());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.base.to_be_bytes());
+    }
+
+    /// `None` if `packet` isn't one of ours
+    fn decode(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < RELIABLE_HEADER || !matches!(packet[0], DATA | ACK) {
+            return None;
+        }
+        let (header, payload) = packet.split_at(RELIABLE_HEADER);
+        let header = Header {
+            kind: header[0],
+            session: u32::from_be_bytes(header[1..5].try_into().ok()?),
+            seq: u64::from_be_bytes(header[5..13].try_into().ok()?),
+            base: u64::from_be_bytes(header[13..21].try_into().ok()?),
+        };
+        Some((header, payload))
+    }
+}
+
+/// tells a restarted sender apart from the previous run
+fn new_session() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0);
+    nanos ^ std::process::id().rotate_left(16)
+}
+
+/// nothing to read (yet), or an ICMP error for a previous packet
+fn is_lost(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+    )
+}
+
+struct InFlight {
+    seq: u64,
+    packet: Vec<u8>,
+    sent: Instant,
+    retries: u32,
+}
+
+struct State {
+    next_seq: u64,
+    in_flight: VecDeque<InFlight>,
+    /// packets given up on
+    lost: u64,
+}
+
+/// Sends every result as one sequence numbered datagram, and resends it until
+/// a [`ReliableReceiver`] acks it.
+pub struct Reliable {
+    udp: Udp,
+    session: u32,
+    window: usize,
+    timeout: Duration,
+    /// resends of a packet before giving up
+    max_retries: u32,
+    state: Mutex<State>,
+    metrics: Metrics,
+}
+
+impl Reliable {
+    pub fn new(udp: Udp) -> Self {
+        Self {
+            udp,
+            session: new_session(),
+            window: WINDOW,
+            timeout: TIMEOUT,
+            max_retries: MAX_RETRIES,
+            state: Mutex::new(State {
+                next_seq: 0,
+                in_flight: VecDeque::new(),
+                lost: 0,
+            }),
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// packets in flight before a send blocks, at least 1
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.clamp(1, MAX_EARLY as usize);
+        self
+    }
+
+    /// how long to wait for an ack before resending
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// packets sent but not acked yet
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight.len()
+    }
+
+    /// packets dropped after `max_retries` resends without an ack
+    pub fn lost(&self) -> u64 {
+        self.state.lock().unwrap().lost
+    }
+
+    /// take the acks already there, then if `wait` isn't zero block for up to
+    /// `wait` until one comes in
+    fn read_acks(&self, state: &mut State, wait: Duration) -> Result<()> {
+        let socket = self.udp.socket();
+        let mut buf = [0u8; RELIABLE_HEADER];
+        // the socket is shared with whoever set it up, leave it as it was
+        let timeout = socket.read_timeout()?;
+        socket.set_nonblocking(true)?;
+        let mut acked = false;
+        let result = loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => acked |= self.ack(state, &buf[..len]),
+                Err(e) if is_lost(&e) && (acked || wait.is_zero()) => break Ok(()),
+                Err(e) if is_lost(&e) => {
+                    socket.set_nonblocking(false)?;
+                    socket.set_read_timeout(Some(wait))?;
+                    match socket.recv(&mut buf) {
+                        Ok(len) => {
+                            self.ack(state, &buf[..len]);
+                        }
+                        Err(e) if is_lost(&e) => {}
+                        Err(e) => break Err(e),
+                    }
+                    break Ok(());
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        socket.set_nonblocking(false)?;
+        socket.set_read_timeout(timeout)?;
+        Ok(result?)
+    }
+
+    /// drop what an ack covers, true if anything was
+    fn ack(&self, state: &mut State, packet: &[u8]) -> bool {
+        let next = match Header::decode(packet) {
+            Some((h, _)) if h.kind == ACK && h.session == self.session => h.seq,
+            _ => return false,
+        };
+        let before = state.in_flight.len();
+        while state.in_flight.front().is_some_and(|p| p.seq < next) {
+            state.in_flight.pop_front();
+        }
+        state.in_flight.len() < before
+    }
+
+    /// resend the packets not acked in time, and drop the ones out of
+    /// retries, an error once for those
+    fn resend(&self, state: &mut State) -> Result<()> {
+        let mut given_up = Vec::new();
+        state.in_flight.retain_mut(|p| {
+            if p.sent.elapsed() < self.timeout {
+                return true;
+            }
+            if p.retries >= self.max_retries {
+                given_up.push(p.seq);
+                return false;
+            }
+            // a failed send is a lost packet, resent on the next timeout
+            let _ = self.udp.socket().send(&p.packet);
+            p.sent = Instant::now();
+            p.retries += 1;
+            self.metrics.retry();
+            true
+        });
+        if let Some(first) = given_up.first() {
+            state.lost += given_up.len() as u64;
+            bail!(
+                "no ack for {} packet(s) from {} on after {} retries, dropped",
+                given_up.len(),
+                first,
+                self.max_retries
+            );
+        }
+        Ok(())
+    }
+
+    /// until the oldest packet in flight times out
+    fn wait(&self, state: &State) -> Duration {
+        let wait = state
+            .in_flight
+            .iter()
+            .map(|p| self.timeout.saturating_sub(p.sent.elapsed()))
+            .min()
+            .unwrap_or(self.timeout);
+        wait.max(Duration::from_millis(1))
+    }
+
+    fn pump(&self, state: &mut State) -> Result<()> {
+        let wait = self.wait(state);
+        self.read_acks(state, wait)?;
+        self.resend(state)
+    }
+
+    /// queue up a packet for `buf` and send it
+    fn push(&self, buf: &[u8]) -> Result<usize> {
+        if buf.len() + RELIABLE_HEADER > MAX_DATAGRAM {
+            bail!("payload too large for a datagram: {} bytes", buf.len());
+        }
+        let mut state = self.state.lock().unwrap();
+        self.read_acks(&mut state, Duration::ZERO)?;
+        while state.in_flight.len() >= self.window {
+            self.pump(&mut state)?;
+        }
+        // before queuing, so an error is never about a packet that went out
+        self.resend(&mut state)?;
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let header = Header {
+            kind: DATA,
+            session: self.session,
+            seq,
+            base: state.in_flight.front().map_or(seq, |p| p.seq),
+        };
+        let mut packet = Vec::with_capacity(RELIABLE_HEADER + buf.len());
+        header.encode(&mut packet);
+        packet.extend_from_slice(buf);
+        let _ = self.udp.socket().send(&packet);
+        state.in_flight.push_back(InFlight {
+            seq,
+            packet,
+            sent: Instant::now(),
+            retries: 0,
+        });
+        Ok(buf.len())
+    }
+}
+
+impl Sender for Reliable {
+    /// returns the payload length once it's sent, not acked
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| self.push(buf))
+    }
+
+    /// wait until everything sent is acked
+    fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while !state.in_flight.is_empty() {
+            self.pump(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// messages are payloads sent once, retries are the packets resent
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+struct Peer {
+    session: u32,
+    next: u64,
+    early: BTreeMap<u64, Vec<u8>>,
+}
+
+/// The receiving end of [`Reliable`], acks packets and hands out the payloads
+/// of every peer once and in order.
+pub struct ReliableReceiver {
+    socket: UdpSocket,
+    peers: HashMap<SocketAddr, Peer>,
+    ready: VecDeque<(Vec<u8>, SocketAddr)>,
+    duplicates: u64,
+    early: u64,
+    lost: u64,
+    /// receive buffer of [`ReliableReceiver::recv_from`]
+    buf: Vec<u8>,
+}
+
+impl ReliableReceiver {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            peers: HashMap::new(),
+            ready: VecDeque::new(),
+            duplicates: 0,
+            early: 0,
+            lost: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn bind(addr: &str) -> Result<Self> {
+        Ok(Self::new(UdpSocket::bind(addr)?))
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// packets received more than once
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// packets that came in ahead of a missing one, and were held back
+    pub fn early(&self) -> u64 {
+        self.early
+    }
+
+    /// packets skipped because their sender gave up on them
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// the next payload in order and who sent it, blocks unless the socket
+    /// has a read timeout
+    pub fn recv_from(&mut self) -> Result<(Vec<u8>, SocketAddr)> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.resize(MAX_DATAGRAM, 0);
+        let result = self.recv_into(&mut buf);
+        self.buf = buf;
+        result
+    }
+
+    fn recv_into(&mut self, buf: &mut [u8]) -> Result<(Vec<u8>, SocketAddr)> {
+        loop {
+            if let Some(ready) = self.ready.pop_front() {
+                return Ok(ready);
+            }
+            let (len, from) = self.socket.recv_from(buf)?;
+            self.handle(&buf[..len], from);
+        }
+    }
+
+    /// take in a packet received from `from`, anything but data is ignored
+    pub fn handle(&mut self, packet: &[u8], from: SocketAddr) {
+        let (header, payload) = match Header::decode(packet) {
+            Some((h, payload)) if h.kind == DATA => (h, payload),
+            _ => return,
+        };
+        // a new sender, or a restarted one, may have been acked by a previous run
+        let peer = self.peers.entry(from).or_insert_with(|| Peer {
+            session: header.session,
+            next: header.base,
+            early: BTreeMap::new(),
+        });
+        if peer.session != header.session {
+            *peer = Peer {
+                session: header.session,
+                next: header.base,
+                early: BTreeMap::new(),
+            };
+        }
+
+        // the sender gave up on everything before its base, hand out what's
+        // held of it and move on
+        if header.base > peer.next {
+            let mut held = peer.early.split_off(&header.base);
+            std::mem::swap(&mut held, &mut peer.early);
+            self.lost += header.base - peer.next - held.len() as u64;
+            self.ready
+                .extend(held.into_values().map(|payload| (payload, from)));
+            peer.next = header.base;
+            while let Some(payload) = peer.early.remove(&peer.next) {
+                self.ready.push_back((payload, from));
+                peer.next += 1;
+            }
+        }
+
+        if header.seq < peer.next || peer.early.contains_key(&header.seq) {
+            self.duplicates += 1;
+        } else if header.seq == peer.next {
+            self.ready.push_back((payload.to_vec(), from));
+            peer.next += 1;
+            while let Some(payload) = peer.early.remove(&peer.next) {
+                self.ready.push_back((payload, from));
+                peer.next += 1;
+            }
+        } else if header.seq - peer.next < MAX_EARLY {
+            peer.early.insert(header.seq, payload.to_vec());
+            self.early += 1;
+        }
+
+        // ack duplicates too, the previous ack may be the one lost
+        let mut ack = Vec::with_capacity(RELIABLE_HEADER);
+        Header {
+            kind: ACK,
+            session: header.session,
+            seq: peer.next,
+            base: 0,
+        }
+        .encode(&mut ack);
+        let _ = self.socket.send_to(&ack, from);
+    }
+
+    /// the payloads `handle` made ready
+    pub fn try_recv(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    const COUNT: usize = 200;
+
+    fn payloads() -> Vec<Vec<u8>> {
+        (0..COUNT)
+            .map(|i| format!("result {}", i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_reliable_in_order() {
+        let mut receiver = ReliableReceiver::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.socket().local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            (0..COUNT)
+                .map(|_| receiver.recv_from().unwrap().0)
+                .collect::<Vec<_>>()
+        });
+
+        let sender = Reliable::new(Udp::new(&addr).unwrap()).window(8);
+        for payload in payloads() {
+            sender.send(&payload).unwrap();
+        }
+        sender.flush().unwrap();
+        assert_eq!(sender.in_flight(), 0);
+        assert_eq!(handle.join().unwrap(), payloads());
+    }
+
+    #[test]
+    fn test_reliable_retransmit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let mut receiver = ReliableReceiver::new(socket.try_clone().unwrap());
+        // drop the first copy of every third packet
+        let handle = thread::spawn(move || {
+            let mut dropped = HashSet::new();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1500];
+            while received.len() < COUNT {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                let (header, _) = Header::decode(&buf[..len]).unwrap();
+                if header.seq % 3 == 1 && dropped.insert(header.seq) {
+                    continue;
+                }
+                receiver.handle(&buf[..len], from);
+                while let Some((payload, _)) = receiver.try_recv() {
+                    received.push(payload);
+                }
+            }
+            received
+        });
+
+        let sender = Reliable::new(Udp::new(&addr).unwrap())
+            .window(16)
+            .timeout(Duration::from_millis(10));
+        for payload in payloads() {
+            sender.send(&payload).unwrap();
+        }
+        sender.flush().unwrap();
+        assert_eq!(handle.join().unwrap(), payloads());
+    }
+
+    fn data(session: u32, seq: u64, base: u64, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        Header {
+            kind: DATA,
+            session,
+            seq,
+            base,
+        }
+        .encode(&mut packet);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_reliable_receiver_duplicates() {
+        let mut receiver = ReliableReceiver::bind("127.0.0.1:0").unwrap();
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        receiver.handle(&data(1, 1, 0, b"b"), from);
+        assert!(receiver.try_recv().is_none());
+        receiver.handle(&data(1, 0, 0, b"a"), from);
+        receiver.handle(&data(1, 0, 0, b"a"), from);
+        receiver.handle(&data(1, 1, 0, b"b"), from);
+        receiver.handle(b"not ours", from);
+        assert_eq!(receiver.try_recv().unwrap().0, b"a");
+        assert_eq!(receiver.try_recv().unwrap().0, b"b");
+        assert!(receiver.try_recv().is_none());
+        assert_eq!(receiver.duplicates(), 2);
+        assert_eq!(receiver.early(), 1);
+
+        // the sender restarted
+        receiver.handle(&data(2, 0, 0, b"c"), from);
+        assert_eq!(receiver.try_recv().unwrap().0, b"c");
+
+        // 1 and 3 given up on, 2 held until then
+        receiver.handle(&data(2, 2, 1, b"e"), from);
+        assert!(receiver.try_recv().is_none());
+        receiver.handle(&data(2, 5, 4, b"g"), from);
+        assert_eq!(receiver.try_recv().unwrap().0, b"e");
+        assert!(receiver.try_recv().is_none());
+        receiver.handle(&data(2, 4, 4, b"f"), from);
+        assert_eq!(receiver.try_recv().unwrap().0, b"f");
+        assert_eq!(receiver.try_recv().unwrap().0, b"g");
+        assert_eq!(receiver.lost(), 2);
+    }
+
+    #[test]
+    fn test_reliable_no_ack() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let sender = Reliable::new(Udp::new(&addr).unwrap())
+            .timeout(Duration::from_millis(5))
+            .max_retries(2);
+        sender.send(b"lost").unwrap();
+        assert!(sender.flush().is_err());
+        // dropped, reported once
+        assert_eq!((sender.in_flight(), sender.lost()), (0, 1));
+        sender.flush().unwrap();
+        sender.send(b"next").unwrap();
+        assert_eq!(
+            sender.udp.socket().read_timeout().unwrap(),
+            None,
+            "read timeout left set"
+        );
+    }
+}
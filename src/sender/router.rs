@@ -0,0 +1,219 @@
+//! Routes every result to the senders whose [`Route`] it matches, e.g. alerts
+//! to one collector, metrics to another and everything to a file.
+
+use super::{Sender, Stats};
+use crate::encoder::Record;
+use anyhow::{bail, Result};
+
+/// Which results a sender of a [`Router`] gets.
+pub enum Route {
+    /// every result
+    All,
+    /// results of the pattern with this id
+    Pattern(usize),
+    /// results whose capture group `group`, from 1, is exactly `value`
+    Capture {
+        group: usize,
+        value: Vec<u8>,
+    },
+    Predicate(Box<dyn Fn(&Record) -> bool + Send + Sync>),
+}
+
+impl Route {
+    pub fn capture(group: usize, value: &[u8]) -> Self {
+        Route::Capture {
+            group,
+            value: value.to_vec(),
+        }
+    }
+
+    pub fn predicate<F: Fn(&Record) -> bool + Send + Sync + 'static>(f: F) -> Self {
+        Route::Predicate(Box::new(f))
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        match self {
+            Route::All => true,
+            Route::Pattern(id) => record.pattern_id == *id,
+            Route::Capture { group, value } => group
+                .checked_sub(1)
+                .and_then(|i| record.captures.get(i).copied().flatten())
+                .is_some_and(|m| m.as_bytes() == value.as_slice()),
+            Route::Predicate(f) => f(record),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Strategy {
+    /// every matching route gets the result
+    #[default]
+    Broadcast,
+    /// the matching routes in order until one sends it, e.g. for a fallback
+    FirstSuccess,
+}
+
+/// A sender that fans results out to other senders by [`Route`].
+///
+/// Plain bytes carry no pattern id or captures, so [`Sender::send`] only goes
+/// to [`Route::All`] routes. A result no route matches is dropped.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Route, Box<dyn Sender + Send + Sync>)>,
+    strategy: Strategy,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// routes are tried in the order they are added
+    pub fn route<S: Sender + Send + Sync + 'static>(mut self, route: Route, sender: S) -> Self {
+        self.routes.push((route, Box::new(sender)));
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// `send` every sender of a matching route, returns the bytes sent by all
+    /// of them with broadcast, by the first that succeeded otherwise
+    fn dispatch<F>(&self, matches: impl Fn(&Route) -> bool, send: F) -> Result<usize>
+    where
+        F: Fn(&dyn Sender) -> Result<usize>,
+    {
+        let mut sent = 0;
+        let mut tried = 0;
+        let mut error = None;
+        for (_, sender) in self.routes.iter().filter(|(route, _)| matches(route)) {
+            tried += 1;
+            match send(sender.as_ref()) {
+                Ok(len) if self.strategy == Strategy::FirstSuccess => return Ok(len),
+                Ok(len) => sent += len,
+                // the other routes still get it
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if self.strategy == Strategy::FirstSuccess => {
+                bail!("all {} routes failed, first error: {}", tried, e)
+            }
+            Some(e) => bail!("route failed: {}", e),
+            None => Ok(sent),
+        }
+    }
+}
+
+impl Sender for Router {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.dispatch(
+            |route| matches!(route, Route::All),
+            |sender| sender.send(buf),
+        )
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        self.dispatch(
+            |route| route.matches(record),
+            |sender| sender.send_record(record),
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut error = None;
+        for (_, sender) in &self.routes {
+            if let Err(e) = sender.flush() {
+                error.get_or_insert(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// the stats of all routes together
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for (_, sender) in &self.routes {
+            stats.merge(&sender.stats());
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{Captures, Match};
+    use crate::sender::StdoutSink;
+    use std::sync::Arc;
+
+    type Sink = Arc<StdoutSink<Vec<u8>>>;
+
+    fn sink() -> Sink {
+        Arc::new(StdoutSink::with_writer(Vec::new()))
+    }
+
+    fn output(sink: Sink) -> String {
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        String::from_utf8(sink.into_inner()).unwrap()
+    }
+
+    struct Fail;
+
+    impl Sender for Fail {
+        fn send(&self, _buf: &[u8]) -> Result<usize> {
+            bail!("down")
+        }
+    }
+
+    #[test]
+    fn test_router_broadcast() {
+        let (alerts, metrics, all) = (sink(), sink(), sink());
+        let router = Router::new()
+            .route(Route::Pattern(0), Arc::clone(&alerts))
+            .route(Route::capture(1, b"cpu"), Arc::clone(&metrics))
+            .route(
+                Route::predicate(|r| r.as_bytes().len() > 3),
+                Arc::clone(&metrics),
+            )
+            .route(Route::All, Arc::clone(&all));
+
+        let subject = b"cpu=90";
+        let captures = Captures::new(subject, vec![Some((0, 6)), Some((0, 3))]);
+        let record = Record::new(1, "-", Match::new(subject, 0, 6)).with_captures(&captures);
+        assert_eq!(router.send_record(&record).unwrap(), 18);
+        let record = Record::new(0, "-", Match::new(b"disk", 0, 4));
+        router.send_record(&record).unwrap();
+        router.send(b"raw").unwrap();
+        drop(router);
+
+        assert_eq!(output(alerts), "disk\n");
+        assert_eq!(output(metrics), "cpu=90\ncpu=90\ndisk\n");
+        assert_eq!(output(all), "cpu=90\ndisk\nraw\n");
+    }
+
+    #[test]
+    fn test_router_first_success() {
+        let (fallback, unused) = (sink(), sink());
+        let router = Router::new()
+            .strategy(Strategy::FirstSuccess)
+            .route(Route::All, Fail)
+            .route(Route::All, Arc::clone(&fallback))
+            .route(Route::All, Arc::clone(&unused));
+        router.send(b"abc").unwrap();
+        drop(router);
+        assert_eq!(output(fallback), "abc\n");
+        assert_eq!(output(unused), "");
+
+        let router = Router::new()
+            .strategy(Strategy::FirstSuccess)
+            .route(Route::All, Fail);
+        assert!(router.send(b"abc").is_err());
+        // no route, dropped
+        assert_eq!(Router::new().send(b"abc").unwrap(), 0);
+    }
+}
@@ -0,0 +1,231 @@
+//! HMAC-SHA256 signatures, so the receiver only takes payloads from senders
+//! that share its key.
+//!
+//! A signed payload is the timestamp in ms since the epoch `u64`, a nonce
+//! `u64`, both big endian, the payload and the HMAC of all that, 32 bytes.
+//! [`Verifier`] drops payloads with a bad signature, a timestamp out of its
+//! window or a timestamp and nonce already seen within it.
+
+use super::{Sender, Stats};
+use anyhow::{anyhow, bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// timestamp and nonce
+const HEADER: usize = 16;
+const TAG: usize = 32;
+
+/// bytes a signature adds to a payload
+pub const SIGNATURE_OVERHEAD: usize = HEADER + TAG;
+
+const WINDOW: Duration = Duration::from_secs(30);
+
+/// A shared secret, never printed.
+#[derive(Clone)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.is_empty() {
+            bail!("empty key");
+        }
+        Ok(Key(key.to_vec()))
+    }
+
+    /// the file's bytes, without a trailing newline
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let key = std::fs::read(path).with_context(|| format!("key file {:?}", path))?;
+        let len = key.len() - key.iter().rev().take_while(|b| b"\r\n".contains(b)).count();
+        Key::new(&key[..len]).with_context(|| format!("key file {:?}", path))
+    }
+
+    /// the value of the environment variable `var`
+    pub fn from_env(var: &str) -> Result<Self> {
+        let key = std::env::var_os(var).ok_or_else(|| anyhow!("{} is not set", var))?;
+        Key::new(key.as_encoded_bytes()).with_context(|| format!("key in {}", var))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).expect("hmac takes keys of any length")
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A sender that signs every payload for `S`.
+pub struct Signed<S: Sender> {
+    sender: S,
+    key: Key,
+    nonce: AtomicU64,
+}
+
+impl<S: Sender> Signed<S> {
+    pub fn new(sender: S, key: Key) -> Self {
+        // a random start, so a restarted sender doesn't repeat nonces
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(now_ms());
+        Self {
+            sender,
+            key,
+            nonce: AtomicU64::new(hasher.finish()),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+}
+
+impl<S: Sender> Sender for Signed<S> {
+    /// returns the length of `buf` once it's sent
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        let nonce = self.nonce.fetch_add(1, Ordering::Relaxed);
+        let mut out = Vec::with_capacity(buf.len() + SIGNATURE_OVERHEAD);
+        out.extend_from_slice(&now_ms().to_be_bytes());
+        out.extend_from_slice(&nonce.to_be_bytes());
+        out.extend_from_slice(buf);
+        let mut mac = self.key.mac();
+        mac.update(&out);
+        out.extend_from_slice(&mac.finalize().into_bytes());
+        match self.sender.send(&out)? {
+            sent if sent < out.len() => bail!("short send: {} of {} bytes", sent, out.len()),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+/// Checks the payloads of a [`Signed`] sender.
+pub struct Verifier {
+    key: Key,
+    window: Duration,
+    /// timestamp and nonce of the payloads within the window
+    seen: Mutex<BTreeSet<(u64, u64)>>,
+}
+
+impl Verifier {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            window: WINDOW,
+            seen: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// how far a timestamp may be from now, either way, 30s by default
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// the payload of a signed one, if it's authentic and not a replay
+    pub fn verify<'a>(&self, signed: &'a [u8]) -> Result<&'a [u8]> {
+        if signed.len() < SIGNATURE_OVERHEAD {
+            bail!("too short to be signed: {} bytes", signed.len());
+        }
+        let (data, tag) = signed.split_at(signed.len() - TAG);
+        let mut mac = self.key.mac();
+        mac.update(data);
+        mac.verify_slice(tag)
+            .map_err(|_| anyhow!("bad signature"))?;
+
+        let timestamp = u64::from_be_bytes(data[..8].try_into()?);
+        let nonce = u64::from_be_bytes(data[8..HEADER].try_into()?);
+        let (now, window) = (now_ms(), self.window.as_millis() as u64);
+        if timestamp.abs_diff(now) > window {
+            bail!("timestamp {} ms off", timestamp.abs_diff(now));
+        }
+        let mut seen = self.seen.lock().unwrap();
+        *seen = seen.split_off(&(now.saturating_sub(window), 0));
+        if !seen.insert((timestamp, nonce)) {
+            bail!("replayed nonce {}", nonce);
+        }
+        Ok(&data[HEADER..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<Vec<u8>>>);
+
+    impl Sender for Collect {
+        fn send(&self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_signed_verify() {
+        let key = Key::new(b"secret").unwrap();
+        let signed = Signed::new(Collect::default(), key.clone());
+        assert_eq!(signed.send(b"2023").unwrap(), 4);
+        signed.send(b"2024").unwrap();
+        let sent = signed.get_ref().0.lock().unwrap();
+        assert_eq!(sent[0].len(), 4 + SIGNATURE_OVERHEAD);
+
+        let verifier = Verifier::new(key);
+        assert_eq!(verifier.verify(&sent[0]).unwrap(), b"2023");
+        assert_eq!(verifier.verify(&sent[1]).unwrap(), b"2024");
+        let e = verifier.verify(&sent[0]).unwrap_err();
+        assert!(e.to_string().starts_with("replayed"), "{}", e);
+
+        let mut tampered = sent[1].clone();
+        tampered[HEADER] = b'1';
+        assert!(verifier.verify(&tampered).is_err());
+        let other = Verifier::new(Key::new(b"other").unwrap());
+        assert!(other.verify(&sent[1]).is_err());
+        assert!(verifier.verify(b"short").is_err());
+    }
+
+    #[test]
+    fn test_verify_stale() {
+        let key = Key::new(b"secret").unwrap();
+        let mut stale = (now_ms() - 60_000).to_be_bytes().to_vec();
+        stale.extend_from_slice(&7u64.to_be_bytes());
+        stale.extend_from_slice(b"old");
+        let mut mac = key.mac();
+        mac.update(&stale);
+        stale.extend_from_slice(&mac.finalize().into_bytes());
+
+        assert!(Verifier::new(key.clone()).verify(&stale).is_err());
+        let verifier = Verifier::new(key).window(Duration::from_secs(120));
+        assert_eq!(verifier.verify(&stale).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_key_from_file() {
+        let path = std::env::temp_dir().join(format!("xipin-key-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let key = Key::from_file(&path).unwrap();
+        assert_eq!(key.0, b"secret");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(Key::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use super::{Metrics, Sender, Stats};
+use anyhow::Result;
+use std::io::{self, Stdout, Write};
+use std::sync::Mutex;
+
+/// Writes every result to stdout followed by a delimiter, `\n` by default.
+///
+/// Any other writer can stand in for stdout, e.g. a `Vec<u8>` in tests. It is
+/// flushed by [`Sender::flush`] only, not after every result.
+pub struct StdoutSink<W: Write = Stdout> {
+    writer: Mutex<W>,
+    delimiter: Option<u8>,
+    metrics: Metrics,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        StdoutSink::with_writer(io::stdout())
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> StdoutSink<W> {
+    pub fn with_writer(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            delimiter: Some(b'\n'),
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// `None` to write the results back to back, e.g. when already framed
+    pub fn delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write> Sender for StdoutSink<W> {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| {
+            let mut writer = self.writer.lock().unwrap();
+            writer.write_all(buf)?;
+            if let Some(delimiter) = self.delimiter {
+                writer.write_all(&[delimiter])?;
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufWriter;
+
+    #[test]
+    fn test_stdout_sink() {
+        let sink = StdoutSink::with_writer(Vec::new());
+        assert_eq!(sink.send(b"abc").unwrap(), 3);
+        sink.send(b"def").unwrap();
+        assert_eq!(sink.into_inner(), b"abc\ndef\n");
+
+        let sink = StdoutSink::with_writer(Vec::new()).delimiter(None);
+        sink.send(b"abc").unwrap();
+        sink.send(b"def").unwrap();
+        assert_eq!(sink.into_inner(), b"abcdef");
+
+        let sink = StdoutSink::with_writer(BufWriter::new(Vec::new()));
+        sink.send(b"abc").unwrap();
+        assert!(sink.writer.lock().unwrap().get_ref().is_empty());
+        sink.flush().unwrap();
+        assert_eq!(sink.into_inner().get_ref(), b"abc\n");
+    }
+}
@@ -0,0 +1,390 @@
+//! Results as syslog messages, RFC 5424 or the older BSD format of RFC 3164.
+//!
+//! An RFC 5424 record carries its pattern id, source and offsets as
+//! structured data, e.g.
+//! `<133>1 2023-04-25T05:20:00.123Z host xipin 42 match [match@32473 pattern="1" source="app.log" start="3" end="7"] 2023`.
+//! RFC 3164 has no structured data, only the match goes out. Timestamps are
+//! UTC in both.
+//!
+//! Any sender can carry them, [`Syslog::udp`], [`Syslog::tcp`] with octet
+//! counting, or [`Syslog::dev_log`] for the local syslog daemon.
+
+use super::{Framing, Sender, Stats, Tcp, Udp};
+use crate::encoder::Record;
+use anyhow::Result;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the SD-ID of the structured data, `32473` is the enterprise number
+/// reserved for examples (RFC 5612)
+const SD_ID: &str = "match@32473";
+
+const APP_NAME: &str = "xipin";
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Rfc5424,
+    Rfc3164,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    AuthPriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Informational = 6,
+    Debug = 7,
+}
+
+/// A sender that wraps every result in a syslog message for `S`.
+pub struct Syslog<S: Sender> {
+    sender: S,
+    protocol: Protocol,
+    facility: Facility,
+    severity: Severity,
+    hostname: String,
+    app_name: String,
+    procid: u32,
+}
+
+impl Syslog<Udp> {
+    pub fn udp(addr: &str) -> Result<Self> {
+        Ok(Syslog::new(Udp::new(addr)?))
+    }
+}
+
+impl Syslog<Tcp> {
+    /// octet counted, the framing RFC 5425 and 6587 agree on
+    pub fn tcp(addr: &str) -> Result<Self> {
+        Ok(Syslog::new(Tcp::new(addr)?.framing(Framing::OctetCounting)))
+    }
+}
+
+#[cfg(unix)]
+impl Syslog<super::UnixDatagram> {
+    /// the local syslog daemon, which expects RFC 3164
+    pub fn dev_log() -> Result<Self> {
+        Ok(Syslog::new(super::UnixDatagram::new("/dev/log")?).protocol(Protocol::Rfc3164))
+    }
+}
+
+impl<S: Sender> Syslog<S> {
+    /// RFC 5424 as `user.notice`
+    pub fn new(sender: S) -> Self {
+        Self {
+            sender,
+            protocol: Protocol::default(),
+            facility: Facility::User,
+            severity: Severity::Notice,
+            hostname: hostname(),
+            app_name: APP_NAME.to_string(),
+            procid: std::process::id(),
+        }
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    pub fn severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = hostname.to_string();
+        self
+    }
+
+    /// the app-name of RFC 5424, the tag of RFC 3164
+    pub fn app_name(mut self, app_name: &str) -> Self {
+        self.app_name = app_name.to_string();
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    /// append the message of `msg` to `out`, with the structured data of
+    /// `record` if there is one
+    fn format(&self, msg: &[u8], time: SystemTime, record: Option<&Record>, out: &mut Vec<u8>) {
+        let pri = (self.facility as u8) * 8 + self.severity as u8;
+        let t = Utc::new(time);
+        match self.protocol {
+            Protocol::Rfc5424 => {
+                let _ = write!(
+                    out,
+                    "<{}>1 {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z {} {} {} match ",
+                    pri,
+                    t.year,
+                    t.month,
+                    t.day,
+                    t.hour,
+                    t.minute,
+                    t.second,
+                    t.millis,
+                    header_field(&self.hostname, 255),
+                    header_field(&self.app_name, 48),
+                    self.procid,
+                );
+                match record {
+                    Some(record) => structured_data(record, out),
+                    None => out.push(b'-'),
+                }
+            }
+            Protocol::Rfc3164 => {
+                const MONTHS: [&str; 12] = [
+                    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+                    "Dec",
+                ];
+                let _ = write!(
+                    out,
+                    "<{}>{} {:>2} {:02}:{:02}:{:02} {} {}[{}]:",
+                    pri,
+                    MONTHS[t.month as usize - 1],
+                    t.day,
+                    t.hour,
+                    t.minute,
+                    t.second,
+                    header_field(&self.hostname, 255),
+                    header_field(&self.app_name, 32),
+                    self.procid,
+                );
+            }
+        }
+        out.push(b' ');
+        out.extend_from_slice(msg);
+    }
+}
+
+impl<S: Sender> Sender for Syslog<S> {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() + 96);
+        self.format(buf, SystemTime::now(), None, &mut out);
+        self.sender.send(&out)
+    }
+
+    fn send_record(&self, record: &Record) -> Result<usize> {
+        let mut out = Vec::with_capacity(record.as_bytes().len() + 160);
+        self.format(record.as_bytes(), record.timestamp, Some(record), &mut out);
+        self.sender.send(&out)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sender.flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.sender.stats()
+    }
+}
+
+/// printable ASCII without spaces, `-` if nothing is left
+fn header_field(s: &str, max: usize) -> String {
+    let field: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    match field.is_empty() {
+        true => "-".to_string(),
+        false => field,
+    }
+}
+
+fn structured_data(record: &Record, out: &mut Vec<u8>) {
+    let _ = write!(
+        out,
+        "[{} pattern=\"{}\" source=\"",
+        SD_ID, record.pattern_id
+    );
+    for c in record.source.chars() {
+        // escaped as RFC 5424 6.3.3 says
+        if matches!(c, '"' | '\\' | ']') {
+            out.push(b'\\');
+        }
+        let mut utf8 = [0u8; 4];
+        out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+    }
+    let _ = write!(
+        out,
+        "\" start=\"{}\" end=\"{}\"",
+        record.m.start(),
+        record.m.end()
+    );
+    if let Some(count) = record.count {
+        let _ = write!(out, " count=\"{}\"", count);
+    }
+    out.push(b']');
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    match rc {
+        0 => String::from_utf8_lossy(&buf[..len]).into_owned(),
+        _ => "-".to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "-".to_string())
+}
+
+/// a UTC calendar time
+struct Utc {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    millis: u32,
+}
+
+impl Utc {
+    /// days to civil date as in http://howardhinnant.github.io/date_algorithms.html
+    fn new(time: SystemTime) -> Self {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs() as i64;
+        let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        Utc {
+            year: yoe + era * 400 + (month <= 2) as i64,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+            millis: since.subsec_millis(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::tests::record;
+    use crate::sender::StdoutSink;
+    use std::io::BufReader;
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    fn syslog(protocol: Protocol) -> Syslog<StdoutSink<Vec<u8>>> {
+        let sink = StdoutSink::with_writer(Vec::new()).delimiter(None);
+        Syslog::new(sink)
+            .protocol(protocol)
+            .facility(Facility::Local0)
+            .severity(Severity::Notice)
+            .hostname("host")
+            .app_name("xipin")
+    }
+
+    #[test]
+    fn test_syslog_rfc5424() {
+        let syslog = syslog(Protocol::Rfc5424);
+        let mut record = record(b"at 2023-04");
+        record.source = r#"a "b".log"#;
+        syslog.send_record(&record).unwrap();
+        let out = String::from_utf8(syslog.sender.into_inner()).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                r#"<133>1 2023-04-25T05:20:00.123Z host xipin {} match [match@32473 pattern="1" source="a \"b\".log" start="3" end="7"] 2023"#,
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_syslog_rfc3164() {
+        let syslog = syslog(Protocol::Rfc3164).hostname("my host");
+        syslog.send_record(&record(b"at 2023-04")).unwrap();
+        let out = String::from_utf8(syslog.sender.into_inner()).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "<133>Apr 25 05:20:00 myhost xipin[{}]: 2023",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_utc() {
+        for (secs, expect) in [
+            (0, (1970, 1, 1, 0, 0, 0)),
+            (951782400, (2000, 2, 29, 0, 0, 0)),
+            (1704067199, (2023, 12, 31, 23, 59, 59)),
+        ] {
+            let t = Utc::new(UNIX_EPOCH + Duration::from_secs(secs));
+            assert_eq!((t.year, t.month, t.day, t.hour, t.minute, t.second), expect);
+        }
+    }
+
+    #[test]
+    fn test_syslog_transports() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::udp(&socket.local_addr().unwrap().to_string()).unwrap();
+        syslog.send(b"over udp").unwrap();
+        let mut buf = [0u8; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"<13>1 "));
+        assert!(buf[..len].ends_with(b" match - over udp"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::tcp(&listener.local_addr().unwrap().to_string()).unwrap();
+        syslog.send(b"over tcp").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let frame = Framing::OctetCounting
+            .decode(&mut BufReader::new(conn))
+            .unwrap()
+            .unwrap();
+        assert!(frame.ends_with(b" match - over tcp"));
+    }
+}
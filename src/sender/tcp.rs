@@ -0,0 +1,172 @@
+use super::conn::peer_closed;
+use super::{Framing, Metrics, Sender, Stats};
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 5;
+
+/// Sends every result as one frame over a TCP connection,
+/// reconnecting with exponential backoff when the peer goes away.
+pub struct Tcp {
+    addr: String,
+    framing: Framing,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    /// reconnect attempts per send, 0 to fail right away
+    max_retries: u32,
+    /// `None` after the connection is lost
+    stream: Mutex<Option<TcpStream>>,
+    metrics: Metrics,
+}
+
+impl Tcp {
+    /// connect right away, results are length prefixed by default
+    pub fn new(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            addr: addr.to_string(),
+            framing: Framing::default(),
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            max_retries: MAX_RETRIES,
+            stream: Mutex::new(Some(stream)),
+            metrics: Metrics::new(),
+        })
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// the first reconnect waits `initial`, doubling up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let mut delay = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match TcpStream::connect(&self.addr) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) if attempt >= self.max_retries => {
+                    bail!("reconnect to {} failed: {}", self.addr, e)
+                }
+                Err(_) => {
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Sender for Tcp {
+    /// returns the payload length, a frame is either written whole or an error
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| {
+            let mut frame = Vec::with_capacity(buf.len() + 4);
+            self.framing.encode(buf, &mut frame)?;
+
+            let mut stream = self.stream.lock().unwrap();
+            let mut retried = false;
+            loop {
+                let conn = match stream.take() {
+                    Some(conn) if !peer_closed(&conn) => conn,
+                    _ => self.connect()?,
+                };
+                match (&conn).write_all(&frame) {
+                    Ok(()) => {
+                        *stream = Some(conn);
+                        return Ok(buf.len());
+                    }
+                    // the connection is dropped, a partial frame can't be resumed
+                    Err(e) if retried || self.max_retries == 0 => bail!("tcp send error: {}", e),
+                    Err(_) => {
+                        retried = true;
+                        self.metrics.retry();
+                    }
+                }
+            }
+        })
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_tcp_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let tcp = Tcp::new(&addr).unwrap().framing(Framing::Newline);
+        assert_eq!(tcp.send(b"abc").unwrap(), 3);
+        assert!(tcp.send(b"a\nb").is_err());
+        tcp.send(b"def").unwrap();
+
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        let framing = Framing::Newline;
+        assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"abc");
+        assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"def");
+    }
+
+    #[test]
+    fn test_tcp_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let tcp = Tcp::new(&addr)
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        tcp.send(b"first").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        assert_eq!(
+            Framing::LengthPrefix.decode(&mut reader).unwrap().unwrap(),
+            b"first"
+        );
+        // the peer goes away, the next send reconnects
+        drop(reader);
+        thread::sleep(Duration::from_millis(20));
+        tcp.send(b"second").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        assert_eq!(
+            Framing::LengthPrefix.decode(&mut reader).unwrap().unwrap(),
+            b"second"
+        );
+
+        // nobody listening any more
+        drop(listener);
+        drop(reader);
+        thread::sleep(Duration::from_millis(20));
+        let tcp = tcp.max_retries(1);
+        assert!(tcp.send(b"lost").is_err());
+    }
+}
@@ -1,28 +1,410 @@
-use super::Sender;
+use super::{Metrics, Sender, Stats};
 use anyhow::{bail, Result};
-use std::net::UdpSocket;
-
-const AUTO_FD: &str = "0.0.0.0:0";
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 
 pub struct Udp {
     socket: UdpSocket,
+    metrics: Metrics,
 }
 
 impl Udp {
+    /// from an ephemeral port on any interface, see [`UdpBuilder`] for more
     pub fn new(addr: &str) -> Result<Self> {
-        let socket = UdpSocket::bind(AUTO_FD).expect("unable to bind");
-        if let Err(e) = socket.connect(addr) {
-            bail!(e);
-        };
-        Ok(Self { socket })
+        UdpBuilder::new().build(addr)
+    }
+
+    pub(super) fn socket(&self) -> &UdpSocket {
+        &self.socket
     }
 }
 
 impl Sender for Udp {
     fn send(&self, buf: &[u8]) -> Result<usize> {
-        match self.socket.send(buf) {
+        self.metrics.record(|| match self.socket.send(buf) {
             Ok(s) => Ok(s),
             Err(e) => bail!(e.to_string()),
+        })
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+/// the outgoing interface of multicast datagrams
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MulticastInterface {
+    /// an address of the interface
+    V4(Ipv4Addr),
+    /// the interface index, 0 for the default
+    V6(u32),
+}
+
+/// Socket options of a [`Udp`] sender, set before connecting it.
+///
+/// The destination decides the address family, so an IPv6 destination binds
+/// `[::]:0` unless told otherwise. A multicast group is just the destination,
+/// the multicast options are refused for any other.
+#[derive(Clone, Debug, Default)]
+pub struct UdpBuilder {
+    bind: Option<SocketAddr>,
+    send_buffer: Option<usize>,
+    ttl: Option<u32>,
+    reuse_addr: bool,
+    broadcast: bool,
+    multicast_interface: Option<MulticastInterface>,
+    multicast_loop: Option<bool>,
+    multicast_ttl: Option<u32>,
+}
+
+impl UdpBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the source address and port, to pick the interface
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = Some(addr);
+        self
+    }
+
+    /// `SO_SNDBUF`, the kernel may round it
+    pub fn send_buffer(mut self, size: usize) -> Self {
+        self.send_buffer = Some(size);
+        self
+    }
+
+    /// unicast TTL, or hop limit on IPv6
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// `SO_REUSEADDR`, to bind an address another socket is bound to
+    pub fn reuse_addr(mut self, yes: bool) -> Self {
+        self.reuse_addr = yes;
+        self
+    }
+
+    /// `SO_BROADCAST`, needed to send to a broadcast address
+    pub fn broadcast(mut self, yes: bool) -> Self {
+        self.broadcast = yes;
+        self
+    }
+
+    pub fn multicast_interface(mut self, interface: MulticastInterface) -> Self {
+        self.multicast_interface = Some(interface);
+        self
+    }
+
+    /// whether this host gets its own multicast datagrams, it does by default
+    pub fn multicast_loop(mut self, yes: bool) -> Self {
+        self.multicast_loop = Some(yes);
+        self
+    }
+
+    /// multicast TTL, or hop limit on IPv6, 1 by default to stay on the link
+    pub fn multicast_ttl(mut self, ttl: u32) -> Self {
+        self.multicast_ttl = Some(ttl);
+        self
+    }
+
+    pub fn build(&self, addr: &str) -> Result<Udp> {
+        let dest = match addr.to_socket_addrs()?.find(|dest| {
+            self.bind
+                .is_none_or(|bind| bind.is_ipv4() == dest.is_ipv4())
+        }) {
+            Some(dest) => dest,
+            None => bail!("no address of {} to send to from {:?}", addr, self.bind),
+        };
+        let multicast = self.multicast_interface.is_some()
+            || self.multicast_loop.is_some()
+            || self.multicast_ttl.is_some();
+        if multicast && !dest.ip().is_multicast() {
+            bail!("multicast options for a unicast address: {}", dest);
+        }
+        match (self.multicast_interface, dest) {
+            (Some(MulticastInterface::V4(_)), SocketAddr::V6(_))
+            | (Some(MulticastInterface::V6(_)), SocketAddr::V4(_)) => {
+                bail!("multicast interface of another family than {}", dest)
+            }
+            _ => {}
+        }
+
+        let bind = self.bind.unwrap_or(match dest {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        });
+        let socket = match self.reuse_addr {
+            true => sys::bind_reuse_addr(&bind),
+            false => UdpSocket::bind(bind),
+        };
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => bail!("unable to bind {}: {}", bind, e),
+        };
+        self.configure(&socket, dest.is_ipv4())?;
+        if let Err(e) = socket.connect(dest) {
+            bail!("unable to connect {}: {}", dest, e);
         }
+        Ok(Udp {
+            socket,
+            metrics: Metrics::new(),
+        })
+    }
+
+    fn configure(&self, socket: &UdpSocket, v4: bool) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            sys::set_send_buffer(socket, size)?;
+        }
+        if let Some(ttl) = self.ttl {
+            match v4 {
+                true => socket.set_ttl(ttl)?,
+                false => sys::set_unicast_hops_v6(socket, ttl)?,
+            }
+        }
+        if self.broadcast {
+            socket.set_broadcast(true)?;
+        }
+        match self.multicast_interface {
+            Some(MulticastInterface::V4(addr)) => sys::set_multicast_if_v4(socket, addr)?,
+            Some(MulticastInterface::V6(index)) => sys::set_multicast_if_v6(socket, index)?,
+            None => {}
+        }
+        if let Some(yes) = self.multicast_loop {
+            match v4 {
+                true => socket.set_multicast_loop_v4(yes)?,
+                false => socket.set_multicast_loop_v6(yes)?,
+            }
+        }
+        if let Some(ttl) = self.multicast_ttl {
+            match v4 {
+                true => socket.set_multicast_ttl_v4(ttl)?,
+                false => sys::set_multicast_hops_v6(socket, ttl)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The options std doesn't have.
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    fn setsockopt<T>(socket: &UdpSocket, level: i32, name: i32, value: T) -> io::Result<()> {
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        match rc {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// close on exec from the start, where there's no `SOCK_CLOEXEC` right after
+    #[cfg(not(target_vendor = "apple"))]
+    const SOCK_TYPE: libc::c_int = libc::SOCK_DGRAM | libc::SOCK_CLOEXEC;
+    #[cfg(target_vendor = "apple")]
+    const SOCK_TYPE: libc::c_int = libc::SOCK_DGRAM;
+
+    /// `SO_REUSEADDR` has to be set between creating and binding the socket
+    pub fn bind_reuse_addr(addr: &SocketAddr) -> io::Result<UdpSocket> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let (domain, len) = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                (libc::AF_INET, mem::size_of::<libc::sockaddr_in>())
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                (libc::AF_INET6, mem::size_of::<libc::sockaddr_in6>())
+            }
+        };
+        let fd = unsafe { libc::socket(domain, SOCK_TYPE, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // owned from here on, closed on every error below
+        let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+        #[cfg(target_vendor = "apple")]
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        setsockopt(
+            &socket,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            1 as libc::c_int,
+        )?;
+        let rc = unsafe {
+            libc::bind(
+                fd,
+                &storage as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        match rc {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(socket),
+        }
+    }
+
+    pub fn set_send_buffer(socket: &UdpSocket, size: usize) -> io::Result<()> {
+        let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+        setsockopt(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, size)
+    }
+
+    pub fn set_unicast_hops_v6(socket: &UdpSocket, hops: u32) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            hops as libc::c_int,
+        )
+    }
+
+    pub fn set_multicast_hops_v6(socket: &UdpSocket, hops: u32) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_HOPS,
+            hops as libc::c_int,
+        )
+    }
+
+    pub fn set_multicast_if_v4(socket: &UdpSocket, addr: Ipv4Addr) -> io::Result<()> {
+        let addr = libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        };
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, addr)
+    }
+
+    pub fn set_multicast_if_v6(socket: &UdpSocket, index: u32) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_IF,
+            index as libc::c_int,
+        )
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io::{self, ErrorKind};
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(ErrorKind::Unsupported, "socket option not supported here")
+    }
+
+    pub fn bind_reuse_addr(_addr: &SocketAddr) -> io::Result<UdpSocket> {
+        Err(unsupported())
+    }
+
+    pub fn set_send_buffer(_socket: &UdpSocket, _size: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_unicast_hops_v6(_socket: &UdpSocket, _hops: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_multicast_hops_v6(_socket: &UdpSocket, _hops: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_multicast_if_v4(_socket: &UdpSocket, _addr: Ipv4Addr) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_multicast_if_v6(_socket: &UdpSocket, _index: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv_from(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0u8; 64];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        (buf[..len].to_vec(), from)
+    }
+
+    #[test]
+    fn test_udp_builder_bind() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let source = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let udp = UdpBuilder::new()
+            .bind(source)
+            .reuse_addr(true)
+            .send_buffer(64 * 1024)
+            .ttl(7)
+            .broadcast(true)
+            .build(&addr)
+            .unwrap();
+        assert_eq!(udp.socket().ttl().unwrap(), 7);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let flags = unsafe { libc::fcntl(udp.socket().as_raw_fd(), libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+        assert!(udp.socket().broadcast().unwrap());
+        udp.send(b"abc").unwrap();
+        assert_eq!(recv_from(&receiver), (b"abc".to_vec(), source));
+    }
+
+    #[test]
+    #[ignore = "needs an IPv6 loopback, run with --ignored"]
+    fn test_udp_builder_ipv6() {
+        let receiver = UdpSocket::bind("[::1]:0").unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let udp = UdpBuilder::new().ttl(3).build(&addr).unwrap();
+        udp.send(b"v6").unwrap();
+        assert_eq!(recv_from(&receiver).0, b"v6");
+    }
+
+    #[test]
+    fn test_udp_builder_errors() {
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        // no panic on a bad bind address any more
+        let taken = UdpSocket::bind(v4).unwrap().local_addr().unwrap();
+        let _hold = UdpSocket::bind(taken);
+        assert!(UdpBuilder::new().bind(taken).build("127.0.0.1:9").is_err());
+        assert!(UdpBuilder::new().bind(v4).build("[::1]:9").is_err());
+        assert!(UdpBuilder::new()
+            .multicast_loop(false)
+            .build("127.0.0.1:9")
+            .is_err());
+        assert!(UdpBuilder::new()
+            .multicast_interface(MulticastInterface::V6(0))
+            .build("239.1.2.3:9")
+            .is_err());
     }
 }
@@ -0,0 +1,251 @@
+//! Unix domain socket senders, for a collector on the same host.
+//!
+//! The socket address is a file system path, or on Linux an abstract name
+//! written with a leading `@`, e.g. `@collector`.
+//!
+//! The collector may not be up yet, or may restart and recreate its socket
+//! file, so a missing socket isn't an error until something is sent, and a
+//! refused or closed connection is retried once with a fresh one.
+
+use super::conn::{is_absent, peer_closed};
+use super::{Framing, Metrics, Sender, Stats};
+use anyhow::{bail, Result};
+use std::io::{self, Write};
+use std::os::unix::net::{self, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UnixAddr {
+    Path(PathBuf),
+    /// Linux abstract namespace, no file involved
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddr {
+    pub fn parse(addr: &str) -> Result<Self> {
+        match addr.strip_prefix('@') {
+            Some(name) if cfg!(any(target_os = "linux", target_os = "android")) => {
+                Ok(UnixAddr::Abstract(name.as_bytes().to_vec()))
+            }
+            Some(_) => bail!("abstract socket address not supported: {}", addr),
+            None => Ok(UnixAddr::Path(PathBuf::from(addr))),
+        }
+    }
+
+    pub fn to_socket_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            UnixAddr::Path(path) => SocketAddr::from_pathname(path),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            UnixAddr::Abstract(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            UnixAddr::Abstract(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+/// Sends every result as one frame over a `SOCK_STREAM` unix socket.
+pub struct UnixStream {
+    addr: SocketAddr,
+    framing: Framing,
+    /// `None` until connected, or after the connection is lost
+    stream: Mutex<Option<net::UnixStream>>,
+    metrics: Metrics,
+}
+
+impl UnixStream {
+    /// results are length prefixed by default
+    pub fn new(addr: &str) -> Result<Self> {
+        let addr = UnixAddr::parse(addr)?.to_socket_addr()?;
+        let stream = match net::UnixStream::connect_addr(&addr) {
+            Ok(stream) => Some(stream),
+            Err(e) if is_absent(&e) => None,
+            Err(e) => bail!(e),
+        };
+        Ok(Self {
+            addr,
+            framing: Framing::default(),
+            stream: Mutex::new(stream),
+            metrics: Metrics::new(),
+        })
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+}
+
+impl Sender for UnixStream {
+    /// returns the payload length, a frame is either written whole or an error
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| {
+            let mut frame = Vec::with_capacity(buf.len() + 4);
+            self.framing.encode(buf, &mut frame)?;
+
+            let mut stream = self.stream.lock().unwrap();
+            let mut retried = false;
+            loop {
+                let conn = match stream.take() {
+                    Some(conn) if !peer_closed(&conn) => conn,
+                    _ => net::UnixStream::connect_addr(&self.addr)?,
+                };
+                match (&conn).write_all(&frame) {
+                    Ok(()) => {
+                        *stream = Some(conn);
+                        return Ok(buf.len());
+                    }
+                    Err(e) if retried => bail!("unix stream send error: {}", e),
+                    Err(_) => {
+                        retried = true;
+                        self.metrics.retry();
+                    }
+                }
+            }
+        })
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+/// Sends every result as one `SOCK_DGRAM` datagram, no framing needed.
+pub struct UnixDatagram {
+    addr: SocketAddr,
+    /// `None` until connected
+    socket: Mutex<Option<net::UnixDatagram>>,
+    metrics: Metrics,
+}
+
+impl UnixDatagram {
+    pub fn new(addr: &str) -> Result<Self> {
+        let addr = UnixAddr::parse(addr)?.to_socket_addr()?;
+        let socket = match UnixDatagram::connect(&addr) {
+            Ok(socket) => Some(socket),
+            Err(e) if is_absent(&e) => None,
+            Err(e) => bail!(e),
+        };
+        Ok(Self {
+            addr,
+            socket: Mutex::new(socket),
+            metrics: Metrics::new(),
+        })
+    }
+
+    fn connect(addr: &SocketAddr) -> io::Result<net::UnixDatagram> {
+        let socket = net::UnixDatagram::unbound()?;
+        socket.connect_addr(addr)?;
+        Ok(socket)
+    }
+}
+
+impl Sender for UnixDatagram {
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.metrics.record(|| {
+            let mut socket = self.socket.lock().unwrap();
+            let conn = match &mut *socket {
+                Some(conn) => conn,
+                None => socket.insert(UnixDatagram::connect(&self.addr)?),
+            };
+            match conn.send(buf) {
+                Ok(len) => return Ok(len),
+                // a recreated socket file is a new socket, connect to it again
+                Err(e) if is_absent(&e) => {}
+                Err(e) => bail!(e),
+            }
+            *socket = None;
+            self.metrics.retry();
+            let fresh = UnixDatagram::connect(&self.addr)?;
+            let len = fresh.send(buf)?;
+            *socket = Some(fresh);
+            Ok(len)
+        })
+    }
+
+    fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::BufReader;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_unix_stream() {
+        let path = temp_path("unix-stream");
+        let addr = path.to_str().unwrap();
+        // the collector isn't up yet
+        let sender = UnixStream::new(addr).unwrap();
+        assert!(sender.send(b"early").is_err());
+
+        let listener = net::UnixListener::bind(&path).unwrap();
+        sender.send(b"abc").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        let framing = Framing::LengthPrefix;
+        assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"abc");
+
+        // the collector restarts with a new socket file
+        drop(reader);
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+        let listener = net::UnixListener::bind(&path).unwrap();
+        sender.send(b"def").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        assert_eq!(framing.decode(&mut reader).unwrap().unwrap(), b"def");
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn recv(socket: &net::UnixDatagram) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn test_unix_datagram() {
+        let path = temp_path("unix-datagram");
+        let sender = UnixDatagram::new(path.to_str().unwrap()).unwrap();
+        assert!(sender.send(b"early").is_err());
+
+        let socket = net::UnixDatagram::bind(&path).unwrap();
+        assert_eq!(sender.send(b"abc").unwrap(), 3);
+        assert_eq!(recv(&socket), b"abc");
+
+        drop(socket);
+        fs::remove_file(&path).unwrap();
+        let socket = net::UnixDatagram::bind(&path).unwrap();
+        sender.send(b"def").unwrap();
+        assert_eq!(recv(&socket), b"def");
+        fs::remove_file(Path::new(&path)).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unix_abstract() {
+        let name = format!("@xipin-test-{}", std::process::id());
+        let addr = UnixAddr::parse(&name).unwrap().to_socket_addr().unwrap();
+        let socket = net::UnixDatagram::bind_addr(&addr).unwrap();
+        let sender = UnixDatagram::new(&name).unwrap();
+        sender.send(b"abstract").unwrap();
+        assert_eq!(recv(&socket), b"abstract");
+    }
+}
@@ -0,0 +1,153 @@
+//! End to end, the `xipin-resolution` binary.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Output, Stdio};
+
+const TARGET: &str = "a;jhgoqoghqoj0329 u0tyu10hg0h9Y0Y9827342482y(Y0y(G)_)lajf;lqjfgqhgpqjopjqa=)*(^!@#$%^&*())9999999";
+
+/// run the binary with `stdin`
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_xipin-resolution"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the binary may exit before reading it, e.g. on a bad option
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_cli_stdin_to_stdout() {
+    let output = run(
+        &["--format", "raw", r"(?<=\d{4})[^\d\s]{3,11}(?=\S)"],
+        TARGET,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"y(Y\n");
+
+    let output = run(
+        &["-ix", "-e", r"ERROR \s (\w+)", "--format", "csv"],
+        "error disk\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("0,-,0,10,"), "{}", stdout);
+    assert!(stdout.ends_with(",error disk,disk\n"), "{}", stdout);
+
+    assert_eq!(run(&["nothing"], TARGET).status.code(), Some(1));
+    assert_eq!(run(&["("], TARGET).status.code(), Some(2));
+    assert_eq!(run(&[], TARGET).status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_captures_of_every_match() {
+    // `\K` reports a start after where matching began
+    let output = run(&["--format", "csv", r"(foo)\Kbar"], "foobar foobar\n");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert!(lines[0].starts_with("0,-,3,6,"), "{}", stdout);
+    assert!(lines[1].starts_with("0,-,10,13,"), "{}", stdout);
+    assert!(
+        lines.iter().all(|line| line.ends_with(",bar,foo")),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_cli_grep() {
+    let log = "boot\n2023 disk full\nok\n2024 disk full\nend\n";
+    let output = run(&["-n", "-C1", r"\d{4}"], log);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "1-boot\n2:2023 disk full\n3-ok\n4:2024 disk full\n5-end\n"
+    );
+
+    let output = run(&["-cv", "disk"], log);
+    assert_eq!(output.stdout, b"3\n");
+    let output = run(&["-Hbo", "--color=always", "end"], log);
+    assert_eq!(
+        output.stdout,
+        b"\x1b[35m\x1b[K-\x1b[m\x1b[K\x1b[36m\x1b[K:\x1b[m\x1b[K\x1b[32m\x1b[K38\x1b[m\x1b[K\x1b[36m\x1b[K:\x1b[m\x1b[K\x1b[01;31m\x1b[Kend\x1b[m\x1b[K\n"
+    );
+    let output = run(&["-l", "end", "-"], log);
+    assert_eq!(output.stdout, b"-\n");
+}
+
+#[test]
+fn test_cli_walk() {
+    let root = std::env::temp_dir().join(format!("xipin-cli-walk-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("logs")).unwrap();
+    for (file, text) in [
+        (".gitignore", "*.tmp\n"),
+        ("a.log", "2023 disk full\n"),
+        ("b.tmp", "2023 disk full\n"),
+        ("c.bin", "2023\0disk\n"),
+        ("logs/d.log", "ok\n2024 disk full\n"),
+        ("logs/e.txt", "2025 disk full\n"),
+    ] {
+        std::fs::write(root.join(file), text).unwrap();
+    }
+    let dir = root.to_str().unwrap();
+    let output = run(&["-j4", "-n", r"\d{4}", dir], "");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected = format!(
+        "{0}/a.log:1:2023 disk full\n{0}/logs/d.log:2:2024 disk full\n{0}/logs/e.txt:1:2025 disk full\n",
+        dir
+    );
+    assert_eq!(stdout, expected);
+
+    let output = run(
+        &[
+            "-l",
+            "--no-ignore",
+            "-a",
+            "--include",
+            "*.[bt]*",
+            "disk",
+            dir,
+        ],
+        "",
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        format!("{0}/b.tmp\n{0}/c.bin\n{0}/logs/e.txt\n", dir)
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_to_receiver() {
+    let mut receiver = Command::new(env!("CARGO_BIN_EXE_receiver"))
+        .args(["-n", "1", "udp://127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(receiver.stderr.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line.trim().strip_prefix("listening on ").unwrap();
+
+    let dest = format!("udp://{}", addr);
+    let output = run(&["-d", &dest, r"(?<=\d{4})[^\d\s]{3,11}(?=\S)"], TARGET);
+    assert_eq!(output.status.code(), Some(0));
+
+    assert!(receiver.wait().unwrap().success());
+    let mut stdout = String::new();
+    receiver
+        .stdout
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(stdout, "y(Y\n");
+}
@@ -0,0 +1,123 @@
+//! End to end, senders against the `receiver` binary.
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, ChildStderr, Command, Stdio};
+
+use xipin_resolution::encoder::{Encoded, Format, Record};
+use xipin_resolution::matcher::PCRE2;
+use xipin_resolution::sender::{Batched, Key, Reliable, Sender, Signed, Tcp, Udp};
+
+const SUBJECT: &[u8] = b"2023 abc 2024 def 2025 ghi";
+
+/// start a receiver and wait for the address it listens on
+fn receiver(args: &[&str]) -> (Child, ChildStderr, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_receiver"))
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let addr = line
+        .trim()
+        .strip_prefix("listening on ")
+        .unwrap()
+        .to_string();
+    (child, stderr.into_inner(), addr)
+}
+
+fn send_all<S: Sender>(sender: &S) {
+    let re = PCRE2::new(r"\d{4}").unwrap();
+    for m in re.find_iter(SUBJECT) {
+        sender
+            .send_record(&Record::new(0, "test", m.unwrap()))
+            .unwrap();
+    }
+    sender.flush().unwrap();
+}
+
+/// the receiver's output and its final report
+fn output(mut child: Child, mut stderr: ChildStderr) -> (String, String) {
+    assert!(child.wait().unwrap().success());
+    let mut stdout = String::new();
+    child.stdout.unwrap().read_to_string(&mut stdout).unwrap();
+    let mut report = String::new();
+    stderr.read_to_string(&mut report).unwrap();
+    (stdout, report)
+}
+
+#[test]
+fn test_receiver_reliable_udp() {
+    let (child, stderr, addr) = receiver(&["--reliable", "-n", "3", "udp://127.0.0.1:0"]);
+    let sender = Encoded::new(Reliable::new(Udp::new(&addr).unwrap()), Format::Raw);
+    send_all(&sender);
+    let (stdout, report) = output(child, stderr);
+    assert_eq!(stdout, "2023\n2024\n2025\n");
+    assert!(report.contains("records 3"), "{}", report);
+    assert!(report.contains(" lost 0,"), "{}", report);
+}
+
+#[test]
+fn test_receiver_tcp_batched_msgpack() {
+    let (child, stderr, addr) = receiver(&[
+        "--batched",
+        "--format",
+        "msgpack",
+        "-n",
+        "3",
+        "tcp://127.0.0.1:0",
+    ]);
+    let sender = Encoded::new(Batched::new(Tcp::new(&addr).unwrap()), Format::MsgPack);
+    send_all(&sender);
+    let (stdout, report) = output(child, stderr);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(
+        lines[1].starts_with(r#"{"pattern":0,"source":"test","start":9,"end":13,"match":"2024""#)
+    );
+    assert!(report.contains("packets 1 records 3"), "{}", report);
+}
+
+#[test]
+fn test_receiver_signed_udp() {
+    let path = std::env::temp_dir().join(format!("xipin-receiver-key-{}", std::process::id()));
+    std::fs::write(&path, "secret\n").unwrap();
+    let key_file = path.to_str().unwrap();
+    let (child, stderr, addr) = receiver(&["--key-file", key_file, "-n", "3", "udp://127.0.0.1:0"]);
+    // injected, not signed
+    Udp::new(&addr).unwrap().send(b"1999").unwrap();
+    let udp = Signed::new(Udp::new(&addr).unwrap(), Key::from_file(&path).unwrap());
+    send_all(&Encoded::new(udp, Format::Raw));
+    let (stdout, report) = output(child, stderr);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(stdout, "2023\n2024\n2025\n");
+    assert!(report.contains("rejected 1"), "{}", report);
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn test_receiver_udp_batched_zstd() {
+    use xipin_resolution::sender::{Compressed, Compression};
+
+    let (child, stderr, addr) =
+        receiver(&["--compressed", "--batched", "-n", "3", "udp://127.0.0.1:0"]);
+    let udp = Compressed::new(Udp::new(&addr).unwrap(), Compression::Zstd(3));
+    send_all(&Encoded::new(Batched::new(udp), Format::Raw));
+    let (stdout, report) = output(child, stderr);
+    assert_eq!(stdout, "2023\n2024\n2025\n");
+    assert!(report.contains("packets 1 records 3"), "{}", report);
+}
+
+#[test]
+fn test_receiver_bad_stats_interval() {
+    for secs in ["-1", "NaN", "inf", "0", "soon"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_receiver"))
+            .args(["--stats", secs])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "--stats {}", secs);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with("--stats needs"), "{}", stderr);
+    }
+}